@@ -11,38 +11,18 @@ DISK_DIAMETER = a0 = 1
 DISK_VOLUME = (DISK_DIAMETER / 2) ** 2 * np.pi
 
 
-def pressure_from_yaml(file_path: str, plot=False) -> dict:
+def pressure_from_yaml(file_path: str) -> dict:
     packing_fraction = float(file_path.split("_")[2].strip(".yaml"))
-    disk_surface_area = np.pi * (0.5) ** 2
-    number_density = packing_fraction / disk_surface_area
 
     with open(file_path) as f:
         results = yaml.safe_load(f)
 
-    df = pd.DataFrame(
-        {"g": results["g_of_r"]["normalized_g"], "r": results["g_of_r"]["r"]}
-    )
-
-    coefs = np.polyfit(df["r"], df["g"], deg=4)
-    xs = np.linspace(min(df["r"]), max(df["r"]))
-    ys = (
-        coefs[0] * xs**4
-        + coefs[1] * xs**3
-        + coefs[2] * xs**2
-        + coefs[3] * xs
-        + coefs[4]
-    )
-
-    value_at_0 = np.sum(coefs)
-
-    p_over_kt = number_density * (1 + 2 * packing_fraction * value_at_0)
-
-    if plot:
-        sns.scatterplot(data=df, x="r", y="g")
-        plt.plot(xs, ys)
-        plt.show()
-
-    return {"pressure": p_over_kt, "density": packing_fraction}
+    # The only pressure of the run, its error comes from a blocking analysis
+    return {
+        "pressure": results["pressure"]["value"],
+        "pressure_err": results["pressure"]["error"],
+        "density": packing_fraction,
+    }
 
 
 def average_volume_from_packing_fraction(packing_fraction: float) -> float:
@@ -111,7 +91,7 @@ if __name__ == "__main__":
 
     nvt_files = glob.glob("./results/results_nvt*")
     df_nvt = pd.DataFrame.from_records(
-        [pressure_from_yaml(f, plot=False) for f in nvt_files]
+        [pressure_from_yaml(f) for f in nvt_files]
     )
     df_nvt["origin"] = "NVT"
     df_nvt["pressure"] = df_nvt["pressure"].apply(lambda x: x)
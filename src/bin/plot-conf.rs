@@ -42,16 +42,20 @@ fn main() -> process::ExitCode {
     for string in lines[1..lines.len()].iter() {
         let mut x: f32 = 0.0;
         let mut y: f32 = 0.0;
+        // Older files only have x and y, all disks have the radius of the header
+        let mut radius: f32 = sigma;
         for (id, string) in string.split_whitespace().enumerate() {
             if id == 0 {
                 x = string.parse().unwrap();
             } else if id == 1 {
                 y = string.parse().unwrap();
+            } else if id == 2 {
+                radius = string.parse().unwrap();
             }
         }
         let circle = Drawing::new()
             .with_shape(Shape::Circle {
-                radius: (sigma * scaling) as u32,
+                radius: (radius * scaling) as u32,
             })
             .with_xy(x * scaling, y * scaling)
             .with_style(Style::stroked(1, Color::black()));
@@ -1,15 +1,234 @@
 use serde::{Deserialize, Serialize};
 use serde_yaml::{self};
 
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Algorithm {
+    #[default]
+    Metropolis,
+    EventChain,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ContactFit {
+    Linear,
+    Quadratic,
+    #[default]
+    Quartic,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum MoveShape {
+    #[default]
+    Square,
+    Disk,
+}
+
+// Polydispersity is the standard deviation of the diameters over their mean
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Composition {
+    #[default]
+    Monodisperse,
+    Binary {
+        fraction_large: f64,
+        size_ratio: f64,
+    },
+    Gaussian {
+        polydispersity: f64,
+    },
+    Uniform {
+        polydispersity: f64,
+    },
+    LogNormal {
+        polydispersity: f64,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RadialDistributionConfig {
+    // Defaults to half the smallest box length
+    pub r_max: Option<f64>,
+    pub bin_width: f64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum NeighborMethod {
+    #[default]
+    Cutoff,
+    Voronoi,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Psi6Config {
+    // Disks closer than the cutoff, or sharing a Voronoi edge
+    #[serde(default)]
+    pub neighbors: NeighborMethod,
+    // Neighbor cutoff, defaults to 1.4 mean diameters
+    pub cutoff: Option<f64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct G6Config {
+    #[serde(default)]
+    pub neighbors: NeighborMethod,
+    // Neighbor cutoff for the local ψ6, defaults to 1.4 mean diameters
+    pub cutoff: Option<f64>,
+    // Defaults to half the smallest box length
+    pub r_max: Option<f64>,
+    pub bin_width: f64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StructureFactorConfig {
+    pub k_max: f64,
+    // Width of the bins of the radial average
+    pub bin_width: f64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DynamicsConfig {
+    // Wave vector of F_s(k, t), defaults to 2π over the mean diameter
+    pub k: Option<f64>,
+    // Longest lag, in sampling intervals
+    pub max_lag: u32,
+    // For the lags and for the time origins within each cycle of max_lag
+    pub points_per_decade: Option<u32>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum TrajectoryProperty {
+    // Local ψ6, with the neighbors of the psi6 section
+    Psi6,
+    CellId,
+    // Unwrapped displacement since the start of the run
+    Displacement,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum TrajectoryFormat {
+    #[default]
+    Xyz,
+    Gsd,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TrajectoryConfig {
+    // Extended XYZ or HOOMD gsd
+    #[serde(default)]
+    pub format: TrajectoryFormat,
+    // Defaults to trajectory.xyz or trajectory.gsd
+    pub file: Option<String>,
+    // Steps between two frames
+    pub stride: u32,
+    // Per-disk columns besides species, position and radius
+    #[serde(default)]
+    pub properties: Vec<TrajectoryProperty>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TimeSeriesConfig {
+    // Defaults to time_series.csv
+    pub file: Option<String>,
+    // Steps between two rows, from the initial configuration on and through
+    // the equilibration. NPT runs write at most one row per sweep of n_disk
+    // steps, at the end of the sweep. The acceptance rates are only counted
+    // in the production.
+    pub stride: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WidomConfig {
+    // Ghost insertions per sample
+    pub insertions: u32,
+    // Defaults to the mean radius
+    pub radius: Option<f64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReplicaExchangeConfig {
+    // βP of each replica, swaps are tried between neighbors in this list
+    pub pressures: Vec<f64>,
+    // Sweeps between two rounds of swap attempts
+    pub swap_interval: u32,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Config {
     pub n_disk: u32,
     pub packing_fraction: f64,
-    pub n_step: u32,
+    #[serde(default)]
+    pub composition: Composition,
+    // Lengths of the production, where observables are accumulated, and of
+    // the equilibration before it, where move sizes are tuned. Both count
+    // single-disk trial moves for metropolis in NVT, NPT (with a volume move
+    // every n_disk of them) and μVT (where insertions and deletions count
+    // too), and event chains for event_chain.
+    #[serde(alias = "n_step")]
+    pub n_production_steps: u32,
+    pub n_equilibration_steps: Option<u32>,
     pub pressure: Option<f64>,
+    // z = exp(βμ) / Λ², insertions and deletions of disks at this activity
+    // when present
+    pub activity: Option<f64>,
+    #[serde(default)]
+    pub algorithm: Algorithm,
+    // Total displacement of one event chain, defaults to the box length along x
+    pub chain_length: Option<f64>,
+    // Polynomial used to extrapolate g(r) to contact for the pressure
+    #[serde(default)]
+    pub contact_fit: ContactFit,
+    // Trial displacements are drawn uniformly in a square or a disk
+    #[serde(default)]
+    pub move_shape: MoveShape,
+    // Initial step size, tuned during the equilibration
+    pub max_displacement: Option<f64>,
+    pub target_acceptance: Option<f64>,
+    // Initial amplitude of the NPT volume moves, defaults to 2 kT / P, tuned
+    // during the equilibration
+    pub max_volume_change: Option<f64>,
+    pub target_volume_acceptance: Option<f64>,
+    pub seed: Option<u64>,
+    // Steps between two checkpoints, no checkpoint is written without it
+    pub checkpoint_interval: Option<u32>,
+    pub checkpoint_file: Option<String>,
+    // Resume the run saved in this checkpoint
+    pub restart_file: Option<String>,
+    // Start from a file written by a previous run (e.g. final.txt), or the
+    // last frame of a gsd file, instead of a hexagonal lattice
+    pub initial_configuration: Option<String>,
+    // Full range g(r), only computed when present
+    pub radial_distribution: Option<RadialDistributionConfig>,
+    // Bond-orientational order time series, only computed when present
+    pub psi6: Option<Psi6Config>,
+    // Orientational correlation function, only computed when present
+    pub g6_of_r: Option<G6Config>,
+    // Disclinations and dislocations of the Voronoi network
+    #[serde(default)]
+    pub defects: bool,
+    // Static structure factor, only computed when present
+    pub structure_factor: Option<StructureFactorConfig>,
+    // Mean-squared displacement and F_s(k, t), only computed when present
+    pub dynamics: Option<DynamicsConfig>,
+    // Excess chemical potential from ghost insertions, only computed when
+    // present, in NVT and NPT
+    pub widom: Option<WidomConfig>,
+    // Trajectory, only written when present
+    pub trajectory: Option<TrajectoryConfig>,
+    // CSV file with one row per stride, written during the run
+    pub time_series: Option<TimeSeriesConfig>,
+    // NPT replicas at several pressures instead of a single run, no
+    // checkpoint, trajectory or time series is written
+    pub replica_exchange: Option<ReplicaExchangeConfig>,
 }
 
 impl Config {
+    #[allow(clippy::needless_return)]
     pub fn from_yaml_file(file_path: &str) -> Config {
         let f = std::fs::File::open(file_path).expect("Could not open file.");
         let config: Config = serde_yaml::from_reader(f).expect("Could not read values.");
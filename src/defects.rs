@@ -0,0 +1,133 @@
+use serde::{Deserialize, Serialize};
+
+use crate::order;
+use crate::state;
+
+// Topological defects of the Voronoi network: every disk without six
+// neighbors is a disclination of charge 6 - z, and connected defective disks
+// form clusters. A neutral 5-7 pair is a dislocation, a cluster with a net
+// charge is a free disclination.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct DefectCounts {
+    pub fivefold: u32,
+    pub sevenfold: u32,
+    // Any other coordination
+    pub other: u32,
+    pub clusters: u32,
+    pub dislocations: u32,
+    pub free_disclinations: u32,
+}
+
+// Time series of the defects, as fractions of the number of disks
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct DefectSeries {
+    pub step: Vec<u32>,
+    pub fivefold: Vec<f64>,
+    pub sevenfold: Vec<f64>,
+    pub other: Vec<f64>,
+    pub clusters: Vec<f64>,
+    pub dislocations: Vec<f64>,
+    pub free_disclinations: Vec<f64>,
+}
+
+impl DefectSeries {
+    pub fn update(&mut self, state: &state::State, step: u32) {
+        let counts = count_defects(&order::voronoi_neighbors(state));
+        let n = state.disks.len() as f64;
+        self.step.push(step);
+        self.fivefold.push(counts.fivefold as f64 / n);
+        self.sevenfold.push(counts.sevenfold as f64 / n);
+        self.other.push(counts.other as f64 / n);
+        self.clusters.push(counts.clusters as f64 / n);
+        self.dislocations.push(counts.dislocations as f64 / n);
+        self.free_disclinations
+            .push(counts.free_disclinations as f64 / n);
+    }
+}
+
+pub fn count_defects(neighbors: &[Vec<usize>]) -> DefectCounts {
+    let mut counts = DefectCounts {
+        fivefold: 0,
+        sevenfold: 0,
+        other: 0,
+        clusters: 0,
+        dislocations: 0,
+        free_disclinations: 0,
+    };
+    let is_defect = |disk_id: usize| neighbors[disk_id].len() != 6;
+    for disk_neighbors in neighbors.iter() {
+        match disk_neighbors.len() {
+            6 => {}
+            5 => counts.fivefold += 1,
+            7 => counts.sevenfold += 1,
+            _ => counts.other += 1,
+        }
+    }
+
+    // Flood fill over the defective disks
+    let mut visited = vec![false; neighbors.len()];
+    for start in 0..neighbors.len() {
+        if visited[start] || !is_defect(start) {
+            continue;
+        }
+        visited[start] = true;
+        let mut to_visit: Vec<usize> = vec![start];
+        let mut size = 0;
+        let mut charge: i64 = 0;
+        let mut fivefold = 0;
+        let mut sevenfold = 0;
+        while let Some(disk_id) = to_visit.pop() {
+            size += 1;
+            charge += 6 - neighbors[disk_id].len() as i64;
+            match neighbors[disk_id].len() {
+                5 => fivefold += 1,
+                7 => sevenfold += 1,
+                _ => {}
+            }
+            for neighbor_id in neighbors[disk_id].iter() {
+                if !visited[*neighbor_id] && is_defect(*neighbor_id) {
+                    visited[*neighbor_id] = true;
+                    to_visit.push(*neighbor_id);
+                }
+            }
+        }
+        counts.clusters += 1;
+        if size == 2 && fivefold == 1 && sevenfold == 1 {
+            counts.dislocations += 1;
+        } else if charge != 0 {
+            counts.free_disclinations += 1;
+        }
+    }
+    counts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_count_defects() {
+        let state = state::State::hexagonal_packing(8, 8, 0.7);
+        let counts = count_defects(&order::voronoi_neighbors(&state));
+        assert_eq!(counts.clusters, 0);
+
+        // Only the coordination and the links between defects matter here
+        let mut neighbors: Vec<Vec<usize>> =
+            (0..20).map(|_| vec![10, 11, 12, 13, 14, 15]).collect();
+        // A 5-7 pair
+        neighbors[0] = vec![1, 10, 11, 12, 13];
+        neighbors[1] = vec![0, 10, 11, 12, 13, 14, 15];
+        // An isolated 5-fold disk
+        neighbors[2] = vec![10, 11, 12, 13, 14];
+        // A 5-5-7 cluster
+        neighbors[3] = vec![4, 10, 11, 12, 13];
+        neighbors[4] = vec![3, 5, 10, 11, 12, 13, 14];
+        neighbors[5] = vec![4, 10, 11, 12, 13];
+        let counts = count_defects(&neighbors);
+        assert_eq!(counts.fivefold, 4);
+        assert_eq!(counts.sevenfold, 2);
+        assert_eq!(counts.clusters, 3);
+        assert_eq!(counts.dislocations, 1);
+        assert_eq!(counts.free_disclinations, 2);
+    }
+}
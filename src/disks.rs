@@ -1,19 +1,31 @@
+use crate::config;
 use crate::geometry::distance_sq_periodic;
 use crate::geometry::Box;
 use crate::geometry::Position;
+use rand::seq::SliceRandom;
+use rand::Rng;
+use rand_distr::{Distribution, LogNormal, Normal, Uniform};
+use serde::{Deserialize, Serialize};
 
+#[derive(Serialize, Deserialize, Clone)]
 pub struct Disk {
     pub position: Position,
     pub radius: f64,
+    pub species: u32,
     pub cell_id: usize,
+    // Total displacement since the start of the run, without the periodic
+    // wrapping
+    pub displacement: Position,
 }
 
+#[allow(clippy::needless_return, clippy::needless_borrow)]
 pub fn are_disks_overlapping(disk_1: &Disk, disk_2: &Disk, sim_box: &Box) -> bool {
     let rsq = distance_sq_periodic(&disk_1.position, &disk_2.position, &sim_box);
     let sigma_sq = (disk_1.radius + disk_2.radius) * (disk_1.radius + disk_2.radius);
     return rsq < sigma_sq;
 }
 
+#[allow(clippy::needless_return, clippy::needless_borrow, clippy::ptr_arg)]
 pub fn are_any_disks_overlapping(disks: &Vec<Disk>, sim_box: &Box) -> bool {
     for i in 0..disks.len() {
         for j in 0..disks.len() {
@@ -28,9 +40,81 @@ pub fn are_any_disks_overlapping(disks: &Vec<Disk>, sim_box: &Box) -> bool {
     return false;
 }
 
+// Radii and species of the disks of a given composition, in random order.
+// Continuous distributions have a mean diameter of 1, binary mixtures have
+// small disks of diameter 1.
+pub fn draw_sizes<R: Rng>(
+    composition: &config::Composition,
+    number_of_disks: usize,
+    rng: &mut R,
+) -> (Vec<f64>, Vec<u32>) {
+    let mut diameters: Vec<f64> = Vec::new();
+    let mut species: Vec<u32> = vec![0; number_of_disks];
+    match *composition {
+        config::Composition::Monodisperse => {
+            diameters = vec![1.0; number_of_disks];
+        }
+        config::Composition::Binary {
+            fraction_large,
+            size_ratio,
+        } => {
+            let number_of_large = (fraction_large * number_of_disks as f64).round() as usize;
+            for (i, disk_species) in species.iter_mut().enumerate() {
+                if i < number_of_large {
+                    diameters.push(size_ratio);
+                    *disk_species = 1;
+                } else {
+                    diameters.push(1.0);
+                }
+            }
+        }
+        config::Composition::Gaussian { polydispersity } => {
+            // The truncation below shifts the mean and the width, keep it
+            // beyond 3σ where it only affects about 0.1% of the disks
+            if 1.0 - 3.0 * polydispersity < 0.1 {
+                panic!("Polydispersity too large for a Gaussian distribution, use log_normal");
+            }
+            let distribution = Normal::new(1.0, polydispersity).unwrap();
+            for _ in 0..number_of_disks {
+                // Truncated, so that no disk has a vanishing size
+                let mut diameter = distribution.sample(rng);
+                while diameter < 0.1 {
+                    diameter = distribution.sample(rng);
+                }
+                diameters.push(diameter);
+            }
+        }
+        config::Composition::Uniform { polydispersity } => {
+            let half_width = 3.0_f64.sqrt() * polydispersity;
+            if half_width >= 1.0 {
+                panic!("Polydispersity too large for a uniform distribution");
+            }
+            let distribution = Uniform::new(1.0 - half_width, 1.0 + half_width);
+            for _ in 0..number_of_disks {
+                diameters.push(distribution.sample(rng));
+            }
+        }
+        config::Composition::LogNormal { polydispersity } => {
+            // Parameters of the underlying normal distribution for a mean of 1
+            let sigma_sq = (1.0 + polydispersity * polydispersity).ln();
+            let distribution = LogNormal::new(-sigma_sq / 2.0, sigma_sq.sqrt()).unwrap();
+            for _ in 0..number_of_disks {
+                diameters.push(distribution.sample(rng));
+            }
+        }
+    }
+
+    let mut order: Vec<usize> = (0..number_of_disks).collect();
+    order.shuffle(rng);
+    let radii = order.iter().map(|i| diameters[*i] / 2.0).collect();
+    let species = order.iter().map(|i| species[*i]).collect();
+    (radii, species)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::sample;
     //use crate::disks::are_disks_overlapping;
 
     #[test]
@@ -41,22 +125,59 @@ mod tests {
         let disk_1 = Disk {
             position: Position { x: 0.0, y: 0.0 },
             radius: 2.0,
+            species: 0,
             cell_id: 0,
+            displacement: Position { x: 0.0, y: 0.0 },
         };
 
         let disk_2 = Disk {
             position: Position { x: 0.0, y: 3.0 },
             radius: 2.0,
+            species: 0,
             cell_id: 0,
+            displacement: Position { x: 0.0, y: 0.0 },
         };
 
         let disk_3 = Disk {
             position: Position { x: 0.0, y: 5.0 },
             radius: 2.0,
+            species: 0,
             cell_id: 0,
+            displacement: Position { x: 0.0, y: 0.0 },
         };
         assert!(are_disks_overlapping(&disk_1, &disk_2, &sim_box_1));
         assert!(!are_disks_overlapping(&disk_1, &disk_3, &sim_box_1));
         assert!(are_disks_overlapping(&disk_1, &disk_3, &sim_box_2));
     }
+
+    #[test]
+    fn test_draw_sizes() {
+        let (mut rng, _) = sample::create_rng(Some(42));
+        let binary = config::Composition::Binary {
+            fraction_large: 0.25,
+            size_ratio: 1.4,
+        };
+        let (radii, species) = draw_sizes(&binary, 100, &mut rng);
+        assert_eq!(species.iter().filter(|s| **s == 1).count(), 25);
+        for i in 0..100 {
+            assert_eq!(radii[i], if species[i] == 1 { 0.7 } else { 0.5 });
+        }
+
+        let log_normal = config::Composition::LogNormal {
+            polydispersity: 0.1,
+        };
+        let (radii, _) = draw_sizes(&log_normal, 10000, &mut rng);
+        let mean_diameter = radii.iter().map(|r| 2.0 * r).sum::<f64>() / 10000.0;
+        assert!((mean_diameter - 1.0).abs() < 0.01);
+    }
+
+    #[test]
+    #[should_panic(expected = "too large for a Gaussian")]
+    fn test_wide_gaussian_rejected() {
+        let (mut rng, _) = sample::create_rng(Some(42));
+        let gaussian = config::Composition::Gaussian {
+            polydispersity: 0.4,
+        };
+        draw_sizes(&gaussian, 10, &mut rng);
+    }
 }
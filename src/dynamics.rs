@@ -0,0 +1,235 @@
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+
+use crate::state;
+use crate::stats;
+
+// Mean-squared displacement and self-intermediate scattering function
+// F_s(k, t) = <cos(k.Δr)>, averaged over k along x and y, from the
+// unwrapped displacements relative to the center of mass.
+//
+// Lags are logarithmically spaced, in units of sampling intervals. Time
+// origins are logarithmically spaced too, at the same offsets as the lags
+// within cycles of max_lag samples, so that the whole run is covered with a
+// bounded number of stored origins. Each origin contributes to every lag.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Dynamics {
+    pub k: f64,
+    // Mean number of steps for each lag
+    pub time: Vec<f64>,
+    pub msd: Vec<f64>,
+    pub self_intermediate_scattering: Vec<f64>,
+    lags: Vec<u32>,
+    sample_id: u32,
+    // Origins still in use, oldest first
+    origins: VecDeque<TimeOrigin>,
+    // Sums of the time, MSD, F_s and count for each lag, blocked by the
+    // sample of the origin so that origins in the transient can be dropped
+    sums: stats::BlockSums,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct TimeOrigin {
+    sample_id: u32,
+    step: u32,
+    displacements: Vec<(f64, f64)>,
+}
+
+impl Dynamics {
+    pub fn new(k: f64, max_lag: u32, points_per_decade: u32) -> Dynamics {
+        let lags = log_spaced_lags(max_lag, points_per_decade);
+        let n_lags = lags.len();
+        Dynamics {
+            k,
+            time: vec![0.0; n_lags],
+            msd: vec![0.0; n_lags],
+            self_intermediate_scattering: vec![0.0; n_lags],
+            lags,
+            sample_id: 0,
+            origins: VecDeque::new(),
+            sums: stats::BlockSums::new(4 * n_lags),
+        }
+    }
+
+    pub fn update(&mut self, state: &state::State, step: u32) {
+        // Relative to the center of mass, event chains only go along +x and +y
+        // so the whole system drifts
+        let n_disks = state.disks.len() as f64;
+        let drift_x = state
+            .disks
+            .iter()
+            .map(|disk| disk.displacement.x)
+            .sum::<f64>()
+            / n_disks;
+        let drift_y = state
+            .disks
+            .iter()
+            .map(|disk| disk.displacement.y)
+            .sum::<f64>()
+            / n_disks;
+        let displacements: Vec<(f64, f64)> = state
+            .disks
+            .iter()
+            .map(|disk| (disk.displacement.x - drift_x, disk.displacement.y - drift_y))
+            .collect();
+        let n_lags = self.lags.len();
+        for origin in self.origins.iter() {
+            let lag = self.sample_id - origin.sample_id;
+            if let Ok(lag_index) = self.lags.binary_search(&lag) {
+                let mut msd = 0.0;
+                let mut fs = 0.0;
+                for (position, origin_position) in
+                    displacements.iter().zip(origin.displacements.iter())
+                {
+                    let dx = position.0 - origin_position.0;
+                    let dy = position.1 - origin_position.1;
+                    msd += dx * dx + dy * dy;
+                    fs += 0.5 * ((self.k * dx).cos() + (self.k * dy).cos());
+                }
+                let mut values = vec![0.0; 4 * n_lags];
+                values[lag_index] = (step - origin.step) as f64;
+                values[n_lags + lag_index] = msd / n_disks;
+                values[2 * n_lags + lag_index] = fs / n_disks;
+                values[3 * n_lags + lag_index] = 1.0;
+                self.sums.add(origin.sample_id as usize, &values);
+            }
+        }
+
+        // Forget the origins older than the longest lag
+        let max_lag = *self.lags.last().unwrap_or(&0);
+        while let Some(origin) = self.origins.front() {
+            if self.sample_id - origin.sample_id >= max_lag {
+                self.origins.pop_front();
+            } else {
+                break;
+            }
+        }
+        let offset = self.sample_id % max_lag.max(1);
+        if offset == 0 || self.lags.binary_search(&offset).is_ok() {
+            self.origins.push_back(TimeOrigin {
+                sample_id: self.sample_id,
+                step,
+                displacements,
+            });
+        }
+        self.sample_id += 1;
+    }
+
+    // Forget the origins taken before the cut
+    pub fn discard_samples(&mut self, n_samples: usize) {
+        self.sums.discard_samples(n_samples);
+    }
+
+    pub fn finalize(&mut self) {
+        let sums = self.sums.total();
+        let n_lags = self.lags.len();
+        for lag_index in 0..n_lags {
+            let count = sums[3 * n_lags + lag_index];
+            if count > 0.0 {
+                self.time[lag_index] = sums[lag_index] / count;
+                self.msd[lag_index] = sums[n_lags + lag_index] / count;
+                self.self_intermediate_scattering[lag_index] = sums[2 * n_lags + lag_index] / count;
+            }
+        }
+    }
+}
+
+// Distinct integers from 1 to max_lag, evenly spaced in log
+fn log_spaced_lags(max_lag: u32, points_per_decade: u32) -> Vec<u32> {
+    let mut lags: Vec<u32> = Vec::new();
+    let mut i = 0;
+    loop {
+        let lag = 10f64.powf(i as f64 / points_per_decade as f64).round() as u32;
+        if lag > max_lag {
+            break;
+        }
+        if lags.last() != Some(&lag) {
+            lags.push(lag);
+        }
+        i += 1;
+    }
+    lags
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_log_spaced_lags() {
+        assert_eq!(
+            log_spaced_lags(100, 5),
+            vec![1, 2, 3, 4, 6, 10, 16, 25, 40, 63, 100]
+        );
+    }
+
+    #[test]
+    fn test_log_spaced_origins() {
+        let state = state::State::hexagonal_packing(4, 4, 0.3);
+        let mut dynamics = Dynamics::new(1.0, 100, 5);
+        let offsets = vec![0, 1, 2, 3, 4, 6, 10, 16, 25, 40, 63];
+        for cycle in 0..2 {
+            for step in 0..100 {
+                dynamics.update(&state, 100 * cycle + step);
+            }
+            let origins: Vec<u32> = dynamics
+                .origins
+                .iter()
+                .map(|origin| origin.sample_id)
+                .collect();
+            let expected: Vec<u32> = offsets.iter().map(|offset| 100 * cycle + offset).collect();
+            assert_eq!(origins, expected);
+        }
+    }
+
+    #[test]
+    fn test_ballistic_motion() {
+        let mut state = state::State::hexagonal_packing(4, 4, 0.3);
+        let mut dynamics = Dynamics::new(1.0, 10, 10);
+        for step in 0..20 {
+            dynamics.update(&state, step);
+            // Half the disks move by 0.8 along x, across the boundaries, so
+            // 0.4 relative to the center of mass
+            for disk_id in (0..state.disks.len()).step_by(2) {
+                state.update_disk_coordinates(
+                    disk_id,
+                    state.disks[disk_id].position.x + 0.8,
+                    state.disks[disk_id].position.y,
+                );
+            }
+        }
+        dynamics.finalize();
+        for (lag_index, lag) in dynamics.lags.iter().enumerate() {
+            let distance = 0.4 * *lag as f64;
+            assert!((dynamics.time[lag_index] - *lag as f64).abs() < 1e-10);
+            assert!((dynamics.msd[lag_index] - distance * distance).abs() < 1e-10);
+            let fs = 0.5 * (distance.cos() + 1.0);
+            assert!((dynamics.self_intermediate_scattering[lag_index] - fs).abs() < 1e-10);
+        }
+    }
+
+    #[test]
+    fn test_discard_origins() {
+        let mut state = state::State::hexagonal_packing(4, 4, 0.3);
+        let mut dynamics = Dynamics::new(1.0, 5, 10);
+        for step in 0..20 {
+            dynamics.update(&state, step);
+            // Transient over the first 10 samples, nothing moves afterwards
+            if step < 10 {
+                for disk_id in (0..state.disks.len()).step_by(2) {
+                    state.update_disk_coordinates(
+                        disk_id,
+                        state.disks[disk_id].position.x + 0.8,
+                        state.disks[disk_id].position.y,
+                    );
+                }
+            }
+        }
+        dynamics.discard_samples(10);
+        dynamics.finalize();
+        for lag_index in 0..dynamics.lags.len() {
+            assert_eq!(dynamics.msd[lag_index], 0.0);
+            assert_eq!(dynamics.self_intermediate_scattering[lag_index], 1.0);
+        }
+    }
+}
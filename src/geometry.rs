@@ -1,32 +1,52 @@
 use crate::disks;
+use serde::{Deserialize, Serialize};
 
+#[derive(Serialize, Deserialize, Clone)]
 pub struct Position {
     pub x: f64,
     pub y: f64,
 }
 
+#[derive(Serialize, Deserialize, Clone)]
 pub struct Box {
     pub lx: f64,
     pub ly: f64,
 }
 
+#[derive(Serialize, Deserialize, Clone)]
 pub struct Grid {
     pub nx: u32,
     pub ny: u32,
+    // Largest disk radius the cells were sized for
+    pub max_radius: f64,
+    // Cells are at least this large, so neighbors within that distance are
+    // always in the neighboring cells
+    pub min_cell_size: f64,
     pub cells: Vec<Cell>,
 }
 
+#[derive(Serialize, Deserialize, Clone)]
 pub struct Cell {
     pub disk_ids: Vec<usize>,
     pub neighbor_ids: Vec<usize>,
 }
 
+impl Grid {
+    pub fn cell_id_from_position(&self, position: &Position, sim_box: &Box) -> usize {
+        let ix = (position.x * (self.nx as f64) / sim_box.lx).floor() as u32;
+        let iy = (position.y * (self.ny as f64) / sim_box.ly).floor() as u32;
+        (ix + self.nx * iy) as usize
+    }
+}
+
+#[allow(clippy::needless_return)]
 pub fn distance_sq(pos_1: &Position, pos_2: &Position) -> f64 {
     let dx = pos_1.x - pos_2.x;
     let dy = pos_1.y - pos_2.y;
     return dx * dx + dy * dy;
 }
 
+#[allow(clippy::needless_return)]
 pub fn distance_sq_periodic(pos_1: &Position, pos_2: &Position, sim_box: &Box) -> f64 {
     let mut dx = pos_1.x - pos_2.x;
     let mut dy = pos_1.y - pos_2.y;
@@ -47,6 +67,7 @@ pub fn apply_boundary_conditions(dx: &mut f64, dy: &mut f64, sim_box: &Box) {
     }
 }
 
+#[allow(clippy::needless_return)]
 pub fn put_in_box_x(x: f64, sim_box: &Box) -> f64 {
     let mut new_x = x;
     if new_x > sim_box.lx {
@@ -57,6 +78,7 @@ pub fn put_in_box_x(x: f64, sim_box: &Box) -> f64 {
     return new_x;
 }
 
+#[allow(clippy::needless_return)]
 pub fn put_in_box_y(y: f64, sim_box: &Box) -> f64 {
     let mut new_y = y;
     if new_y > sim_box.ly {
@@ -67,8 +89,16 @@ pub fn put_in_box_y(y: f64, sim_box: &Box) -> f64 {
     return new_y;
 }
 
-pub fn create_grid(disks: &mut Vec<disks::Disk>, sim_box: &Box) -> Grid {
-    let dx_dy = disks[0].radius * 2.2;
+#[allow(
+    clippy::needless_return,
+    clippy::redundant_field_names,
+    clippy::needless_range_loop,
+    clippy::unnecessary_cast,
+    clippy::ptr_arg
+)]
+pub fn create_grid(disks: &mut Vec<disks::Disk>, sim_box: &Box, min_cell_size: f64) -> Grid {
+    let max_radius = disks.iter().map(|disk| disk.radius).fold(0.0, f64::max);
+    let dx_dy = (max_radius * 2.2).max(min_cell_size);
     let nx: i32 = (sim_box.lx / dx_dy).floor() as i32;
     let ny: i32 = (sim_box.ly / dx_dy).floor() as i32;
     let number_of_cells = nx * ny;
@@ -129,6 +159,8 @@ pub fn create_grid(disks: &mut Vec<disks::Disk>, sim_box: &Box) -> Grid {
     return Grid {
         nx: nx as u32,
         ny: ny as u32,
+        max_radius: max_radius,
+        min_cell_size: min_cell_size,
         cells: cells,
     };
 }
@@ -0,0 +1,753 @@
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::disks;
+use crate::geometry;
+use crate::state;
+
+// GSD 2.0 files with the HOOMD schema, the format of HOOMD-blue, freud and
+// the gsd python package. A file is a header, a list of chunk names, an
+// index of (frame, name) -> location entries and the raw chunks.
+const GSD_MAGIC: u64 = 0x65DF65DF65DF65DF;
+const GSD_NAME_SIZE: u64 = 64;
+const HEADER_SIZE: u64 = 256;
+const INDEX_ENTRY_SIZE: u64 = 32;
+const INITIAL_INDEX_ENTRIES: u64 = 128;
+const INITIAL_NAMELIST_ENTRIES: u64 = 64;
+
+const fn make_version(major: u32, minor: u32) -> u32 {
+    (major << 16) | minor
+}
+
+// Type ids of the gsd specification
+#[derive(Debug, Clone, PartialEq)]
+pub enum ChunkData {
+    UInt8(Vec<u8>),
+    UInt32(Vec<u32>),
+    UInt64(Vec<u64>),
+    Int8(Vec<i8>),
+    Float(Vec<f32>),
+    Double(Vec<f64>),
+}
+
+impl ChunkData {
+    fn type_id(&self) -> u8 {
+        match self {
+            ChunkData::UInt8(_) => 1,
+            ChunkData::UInt32(_) => 3,
+            ChunkData::UInt64(_) => 4,
+            ChunkData::Int8(_) => 5,
+            ChunkData::Float(_) => 9,
+            ChunkData::Double(_) => 10,
+        }
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            ChunkData::UInt8(values) => values.len(),
+            ChunkData::UInt32(values) => values.len(),
+            ChunkData::UInt64(values) => values.len(),
+            ChunkData::Int8(values) => values.len(),
+            ChunkData::Float(values) => values.len(),
+            ChunkData::Double(values) => values.len(),
+        }
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            ChunkData::UInt8(values) => values.clone(),
+            ChunkData::UInt32(values) => values.iter().flat_map(|v| v.to_le_bytes()).collect(),
+            ChunkData::UInt64(values) => values.iter().flat_map(|v| v.to_le_bytes()).collect(),
+            ChunkData::Int8(values) => values.iter().flat_map(|v| v.to_le_bytes()).collect(),
+            ChunkData::Float(values) => values.iter().flat_map(|v| v.to_le_bytes()).collect(),
+            ChunkData::Double(values) => values.iter().flat_map(|v| v.to_le_bytes()).collect(),
+        }
+    }
+
+    fn from_bytes(type_id: u8, bytes: &[u8]) -> ChunkData {
+        match type_id {
+            1 => ChunkData::UInt8(bytes.to_vec()),
+            3 => ChunkData::UInt32(
+                bytes
+                    .chunks_exact(4)
+                    .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+                    .collect(),
+            ),
+            4 => ChunkData::UInt64(
+                bytes
+                    .chunks_exact(8)
+                    .map(|b| u64::from_le_bytes(b.try_into().unwrap()))
+                    .collect(),
+            ),
+            5 => ChunkData::Int8(bytes.iter().map(|b| *b as i8).collect()),
+            9 => ChunkData::Float(
+                bytes
+                    .chunks_exact(4)
+                    .map(|b| f32::from_le_bytes(b.try_into().unwrap()))
+                    .collect(),
+            ),
+            10 => ChunkData::Double(
+                bytes
+                    .chunks_exact(8)
+                    .map(|b| f64::from_le_bytes(b.try_into().unwrap()))
+                    .collect(),
+            ),
+            _ => panic!("Unsupported gsd chunk type {}", type_id),
+        }
+    }
+
+    fn type_size(type_id: u8) -> u64 {
+        match type_id {
+            1 | 5 => 1,
+            2 | 6 => 2,
+            3 | 7 | 9 => 4,
+            4 | 8 | 10 => 8,
+            _ => panic!("Unsupported gsd chunk type {}", type_id),
+        }
+    }
+
+    // Numbers of any type, for the fields we only need as f64
+    pub fn to_f64(&self) -> Vec<f64> {
+        match self {
+            ChunkData::UInt8(values) => values.iter().map(|v| *v as f64).collect(),
+            ChunkData::UInt32(values) => values.iter().map(|v| *v as f64).collect(),
+            ChunkData::UInt64(values) => values.iter().map(|v| *v as f64).collect(),
+            ChunkData::Int8(values) => values.iter().map(|v| *v as f64).collect(),
+            ChunkData::Float(values) => values.iter().map(|v| *v as f64).collect(),
+            ChunkData::Double(values) => values.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct IndexEntry {
+    frame: u64,
+    n: u64,
+    location: i64,
+    m: u32,
+    id: u16,
+    type_id: u8,
+}
+
+impl IndexEntry {
+    fn to_bytes(self) -> Vec<u8> {
+        let mut bytes: Vec<u8> = Vec::new();
+        bytes.extend(self.frame.to_le_bytes());
+        bytes.extend(self.n.to_le_bytes());
+        bytes.extend(self.location.to_le_bytes());
+        bytes.extend(self.m.to_le_bytes());
+        bytes.extend(self.id.to_le_bytes());
+        bytes.push(self.type_id);
+        // Flags
+        bytes.push(0);
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> IndexEntry {
+        IndexEntry {
+            frame: u64::from_le_bytes(bytes[0..8].try_into().unwrap()),
+            n: u64::from_le_bytes(bytes[8..16].try_into().unwrap()),
+            location: i64::from_le_bytes(bytes[16..24].try_into().unwrap()),
+            m: u32::from_le_bytes(bytes[24..28].try_into().unwrap()),
+            id: u16::from_le_bytes(bytes[28..30].try_into().unwrap()),
+            type_id: bytes[30],
+        }
+    }
+}
+
+pub struct GsdFile {
+    file: File,
+    index_location: u64,
+    index_allocated_entries: u64,
+    namelist_location: u64,
+    namelist_allocated_entries: u64,
+    index: Vec<IndexEntry>,
+    names: Vec<String>,
+    // Names and entries of the frame being written
+    pending_names: usize,
+    pending_entries: Vec<IndexEntry>,
+}
+
+impl GsdFile {
+    pub fn create(filepath: &Path) -> GsdFile {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(filepath)
+            .expect("Could not create gsd file.");
+        let mut gsd_file = GsdFile {
+            file,
+            index_location: HEADER_SIZE + INITIAL_NAMELIST_ENTRIES * GSD_NAME_SIZE,
+            index_allocated_entries: INITIAL_INDEX_ENTRIES,
+            namelist_location: HEADER_SIZE,
+            namelist_allocated_entries: INITIAL_NAMELIST_ENTRIES,
+            index: Vec::new(),
+            names: Vec::new(),
+            pending_names: 0,
+            pending_entries: Vec::new(),
+        };
+        gsd_file.write_header();
+        let empty_blocks =
+            INITIAL_NAMELIST_ENTRIES * GSD_NAME_SIZE + INITIAL_INDEX_ENTRIES * INDEX_ENTRY_SIZE;
+        gsd_file
+            .file
+            .write_all(&vec![0; empty_blocks as usize])
+            .unwrap();
+        gsd_file
+    }
+
+    pub fn open(filepath: &Path) -> GsdFile {
+        GsdFile::open_with_mode(filepath, false)
+    }
+
+    // Existing file, to add frames to
+    pub fn open_for_append(filepath: &Path) -> GsdFile {
+        GsdFile::open_with_mode(filepath, true)
+    }
+
+    fn open_with_mode(filepath: &Path, append: bool) -> GsdFile {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(append)
+            .open(filepath)
+            .expect("Could not open gsd file.");
+        let mut header = vec![0; HEADER_SIZE as usize];
+        file.read_exact(&mut header)
+            .expect("Could not read gsd header.");
+        let read_u64 =
+            |offset: usize| u64::from_le_bytes(header[offset..offset + 8].try_into().unwrap());
+        if read_u64(0) != GSD_MAGIC {
+            panic!("{} is not a gsd file", filepath.display());
+        }
+        let gsd_version = u32::from_le_bytes(header[44..48].try_into().unwrap());
+        if gsd_version >> 16 != 2 {
+            panic!(
+                "Only gsd 2.x files are supported, {} is version {}.{}",
+                filepath.display(),
+                gsd_version >> 16,
+                gsd_version & 0xffff
+            );
+        }
+        let index_location = read_u64(8);
+        let index_allocated_entries = read_u64(16);
+        let namelist_location = read_u64(24);
+        let namelist_allocated_entries = read_u64(32);
+
+        // Null terminated names, up to the first empty one
+        let mut namelist = vec![0; (namelist_allocated_entries * GSD_NAME_SIZE) as usize];
+        file.seek(SeekFrom::Start(namelist_location)).unwrap();
+        file.read_exact(&mut namelist)
+            .expect("Could not read gsd namelist.");
+        let mut names: Vec<String> = Vec::new();
+        for name in namelist.split(|byte| *byte == 0) {
+            if name.is_empty() {
+                break;
+            }
+            names.push(String::from_utf8_lossy(name).to_string());
+        }
+
+        // Unused entries at the end have a zero location
+        let mut index_bytes = vec![0; (index_allocated_entries * INDEX_ENTRY_SIZE) as usize];
+        file.seek(SeekFrom::Start(index_location)).unwrap();
+        file.read_exact(&mut index_bytes)
+            .expect("Could not read gsd index.");
+        let index: Vec<IndexEntry> = index_bytes
+            .chunks_exact(INDEX_ENTRY_SIZE as usize)
+            .map(IndexEntry::from_bytes)
+            .take_while(|entry| entry.location != 0)
+            .collect();
+
+        GsdFile {
+            file,
+            index_location,
+            index_allocated_entries,
+            namelist_location,
+            namelist_allocated_entries,
+            index,
+            names,
+            pending_names: 0,
+            pending_entries: Vec::new(),
+        }
+    }
+
+    pub fn number_of_frames(&self) -> u64 {
+        self.index.last().map_or(0, |entry| entry.frame + 1)
+    }
+
+    // Chunk of the given frame, None when it is not there
+    pub fn read_chunk(&mut self, frame: u64, name: &str) -> Option<ChunkData> {
+        let id = self.names.iter().position(|n| n == name)? as u16;
+        let entry = *self
+            .index
+            .iter()
+            .find(|entry| entry.frame == frame && entry.id == id)?;
+        let size = entry.n * entry.m as u64 * ChunkData::type_size(entry.type_id);
+        let mut bytes = vec![0; size as usize];
+        self.file
+            .seek(SeekFrom::Start(entry.location as u64))
+            .unwrap();
+        self.file
+            .read_exact(&mut bytes)
+            .expect("Could not read gsd chunk.");
+        Some(ChunkData::from_bytes(entry.type_id, &bytes))
+    }
+
+    // Data has n rows of m columns
+    pub fn write_chunk(&mut self, name: &str, m: u32, data: &ChunkData) {
+        let id = match self.names.iter().position(|n| n == name) {
+            Some(id) => id,
+            None => {
+                self.names.push(name.to_string());
+                self.pending_names += 1;
+                self.names.len() - 1
+            }
+        };
+        let location = self.file.seek(SeekFrom::End(0)).unwrap();
+        self.file.write_all(&data.to_bytes()).unwrap();
+        self.pending_entries.push(IndexEntry {
+            frame: self.number_of_frames(),
+            n: (data.len() / m as usize) as u64,
+            location: location as i64,
+            m,
+            id: id as u16,
+            type_id: data.type_id(),
+        });
+    }
+
+    // Make the chunks written since the last call visible, as one frame
+    pub fn end_frame(&mut self) {
+        if self.pending_names > 0 {
+            self.write_namelist();
+            self.pending_names = 0;
+        }
+        // Readers look entries up by frame, then id
+        let mut entries = std::mem::take(&mut self.pending_entries);
+        entries.sort_by_key(|entry| entry.id);
+        let first_new_entry = self.index.len() as u64;
+        self.index.extend(entries.iter());
+        if self.index.len() as u64 > self.index_allocated_entries {
+            self.relocate_index();
+        } else {
+            let mut bytes: Vec<u8> = Vec::new();
+            for entry in entries.iter() {
+                bytes.extend(entry.to_bytes());
+            }
+            self.file
+                .seek(SeekFrom::Start(
+                    self.index_location + first_new_entry * INDEX_ENTRY_SIZE,
+                ))
+                .unwrap();
+            self.file.write_all(&bytes).unwrap();
+        }
+        self.file.flush().unwrap();
+    }
+
+    // Forget the frames from the given one on. Their chunks stay in the file
+    // but nothing points to them, and the next frames take their numbers.
+    pub fn truncate_frames(&mut self, number_of_frames: u64) {
+        self.index.retain(|entry| entry.frame < number_of_frames);
+        let mut bytes: Vec<u8> = Vec::new();
+        for entry in self.index.iter() {
+            bytes.extend(entry.to_bytes());
+        }
+        bytes.resize(
+            (self.index_allocated_entries * INDEX_ENTRY_SIZE) as usize,
+            0,
+        );
+        self.file
+            .seek(SeekFrom::Start(self.index_location))
+            .unwrap();
+        self.file.write_all(&bytes).unwrap();
+        self.file.flush().unwrap();
+    }
+
+    fn write_namelist(&mut self) {
+        let mut bytes: Vec<u8> = Vec::new();
+        for name in self.names.iter() {
+            bytes.extend(name.as_bytes());
+            bytes.push(0);
+        }
+        // Keep a terminating empty name
+        let needed_entries = (bytes.len() as u64 + 1).div_ceil(GSD_NAME_SIZE);
+        if needed_entries > self.namelist_allocated_entries {
+            self.namelist_allocated_entries = 2 * needed_entries;
+            self.namelist_location = self.file.seek(SeekFrom::End(0)).unwrap();
+        }
+        bytes.resize(
+            (self.namelist_allocated_entries * GSD_NAME_SIZE) as usize,
+            0,
+        );
+        self.file
+            .seek(SeekFrom::Start(self.namelist_location))
+            .unwrap();
+        self.file.write_all(&bytes).unwrap();
+        self.write_header();
+    }
+
+    // Copy the index to a larger block at the end of the file, the old
+    // one stays valid until the header points to the new one
+    fn relocate_index(&mut self) {
+        let allocated_entries = 2 * self.index.len() as u64;
+        let mut bytes: Vec<u8> = Vec::new();
+        for entry in self.index.iter() {
+            bytes.extend(entry.to_bytes());
+        }
+        bytes.resize((allocated_entries * INDEX_ENTRY_SIZE) as usize, 0);
+        let location = self.file.seek(SeekFrom::End(0)).unwrap();
+        self.file.write_all(&bytes).unwrap();
+        self.index_location = location;
+        self.index_allocated_entries = allocated_entries;
+        self.write_header();
+    }
+
+    fn write_header(&mut self) {
+        let mut header: Vec<u8> = Vec::new();
+        header.extend(GSD_MAGIC.to_le_bytes());
+        header.extend(self.index_location.to_le_bytes());
+        header.extend(self.index_allocated_entries.to_le_bytes());
+        header.extend(self.namelist_location.to_le_bytes());
+        header.extend(self.namelist_allocated_entries.to_le_bytes());
+        header.extend(make_version(1, 4).to_le_bytes());
+        header.extend(make_version(2, 0).to_le_bytes());
+        let mut application = b"hard-disks".to_vec();
+        application.resize(64, 0);
+        header.extend(application);
+        let mut schema = b"hoomd".to_vec();
+        schema.resize(64, 0);
+        header.extend(schema);
+        header.resize(HEADER_SIZE as usize, 0);
+        self.file.seek(SeekFrom::Start(0)).unwrap();
+        self.file.write_all(&header).unwrap();
+    }
+}
+
+// Append a HOOMD frame, positions are centered on the origin there. Per-disk
+// quantities go to log/particles/<name>, as n x m chunks.
+pub fn append_frame(
+    gsd_file: &mut GsdFile,
+    state: &state::State,
+    step: u64,
+    per_disk_logs: &[(&str, u32, ChunkData)],
+) {
+    let sim_box = &state.sim_box;
+    let number_of_types = state
+        .disks
+        .iter()
+        .map(|disk| disk.species)
+        .max()
+        .unwrap_or(0)
+        + 1;
+
+    gsd_file.write_chunk("configuration/step", 1, &ChunkData::UInt64(vec![step]));
+    gsd_file.write_chunk("configuration/dimensions", 1, &ChunkData::UInt8(vec![2]));
+    // HOOMD 3 and later, and freud, take a box with Lz = 0 as two
+    // dimensional, Lz = 1 would be read as a thin 3D slab
+    gsd_file.write_chunk(
+        "configuration/box",
+        1,
+        &ChunkData::Float(vec![
+            sim_box.lx as f32,
+            sim_box.ly as f32,
+            0.0,
+            0.0,
+            0.0,
+            0.0,
+        ]),
+    );
+    gsd_file.write_chunk(
+        "particles/N",
+        1,
+        &ChunkData::UInt32(vec![state.disks.len() as u32]),
+    );
+    // Null padded names, one per row
+    let type_names: Vec<String> = (0..number_of_types).map(type_name).collect();
+    let name_width = type_names.iter().map(|name| name.len()).max().unwrap_or(0) + 1;
+    let mut types: Vec<i8> = Vec::new();
+    for name in type_names.iter() {
+        let mut row: Vec<i8> = name.bytes().map(|b| b as i8).collect();
+        row.resize(name_width, 0);
+        types.extend(row);
+    }
+    gsd_file.write_chunk(
+        "particles/types",
+        name_width as u32,
+        &ChunkData::Int8(types),
+    );
+    gsd_file.write_chunk(
+        "particles/typeid",
+        1,
+        &ChunkData::UInt32(state.disks.iter().map(|disk| disk.species).collect()),
+    );
+    let mut positions: Vec<f32> = Vec::new();
+    for disk in state.disks.iter() {
+        positions.push((disk.position.x - sim_box.lx / 2.0) as f32);
+        positions.push((disk.position.y - sim_box.ly / 2.0) as f32);
+        positions.push(0.0);
+    }
+    gsd_file.write_chunk("particles/position", 3, &ChunkData::Float(positions));
+    gsd_file.write_chunk(
+        "particles/diameter",
+        1,
+        &ChunkData::Float(
+            state
+                .disks
+                .iter()
+                .map(|disk| (2.0 * disk.radius) as f32)
+                .collect(),
+        ),
+    );
+    // The schema is single precision, which is not enough to restart a dense
+    // packing without overlaps
+    gsd_file.write_chunk(
+        "log/hard_disks/box",
+        1,
+        &ChunkData::Double(vec![sim_box.lx, sim_box.ly]),
+    );
+    let mut positions: Vec<f64> = Vec::new();
+    let mut radii: Vec<f64> = Vec::new();
+    for disk in state.disks.iter() {
+        positions.push(disk.position.x);
+        positions.push(disk.position.y);
+        radii.push(disk.radius);
+    }
+    gsd_file.write_chunk(
+        "log/particles/hard_disks_position",
+        2,
+        &ChunkData::Double(positions),
+    );
+    gsd_file.write_chunk(
+        "log/particles/hard_disks_radius",
+        1,
+        &ChunkData::Double(radii),
+    );
+    for (name, m, data) in per_disk_logs.iter() {
+        gsd_file.write_chunk(&format!("log/particles/{}", name), *m, data);
+    }
+    gsd_file.end_frame();
+}
+
+// Read one frame, the last one by default. As in HOOMD, chunks missing from
+// a frame are taken from frame 0, or have their default value.
+pub fn read_state(filepath: &Path, frame: Option<u64>) -> state::State {
+    let mut gsd_file = GsdFile::open(filepath);
+    let number_of_frames = gsd_file.number_of_frames();
+    if number_of_frames == 0 {
+        panic!("{} contains no frame", filepath.display());
+    }
+    let frame = frame.unwrap_or(number_of_frames - 1);
+    if frame >= number_of_frames {
+        panic!(
+            "{} only has {} frames, can’t read frame {}",
+            filepath.display(),
+            number_of_frames,
+            frame
+        );
+    }
+    let mut read = |name: &str| -> Option<Vec<f64>> {
+        let chunk = gsd_file
+            .read_chunk(frame, name)
+            .or_else(|| gsd_file.read_chunk(0, name))?;
+        Some(chunk.to_f64())
+    };
+
+    let number_of_disks = read("particles/N").map_or(0, |n| n[0] as usize);
+    let sim_box = match read("log/hard_disks/box") {
+        Some(lengths) => geometry::Box {
+            lx: lengths[0],
+            ly: lengths[1],
+        },
+        None => {
+            let hoomd_box = read("configuration/box").expect("No box in the gsd file.");
+            if hoomd_box[3] != 0.0 {
+                panic!("Only rectangular boxes are supported, the box has a tilt");
+            }
+            geometry::Box {
+                lx: hoomd_box[0],
+                ly: hoomd_box[1],
+            }
+        }
+    };
+    let positions: Vec<(f64, f64)> = match read("log/particles/hard_disks_position") {
+        Some(positions) => positions.chunks_exact(2).map(|p| (p[0], p[1])).collect(),
+        None => read("particles/position")
+            .unwrap_or(vec![0.0; 3 * number_of_disks])
+            .chunks_exact(3)
+            .map(|p| (p[0] + sim_box.lx / 2.0, p[1] + sim_box.ly / 2.0))
+            .collect(),
+    };
+    let radii: Vec<f64> = match read("log/particles/hard_disks_radius") {
+        Some(radii) => radii,
+        None => read("particles/diameter")
+            .unwrap_or(vec![1.0; number_of_disks])
+            .iter()
+            .map(|diameter| diameter / 2.0)
+            .collect(),
+    };
+    let species = read("particles/typeid").unwrap_or(vec![0.0; number_of_disks]);
+
+    let mut disks: Vec<disks::Disk> = Vec::new();
+    for disk_id in 0..number_of_disks {
+        disks.push(disks::Disk {
+            position: geometry::Position {
+                x: geometry::put_in_box_x(positions[disk_id].0, &sim_box),
+                y: geometry::put_in_box_y(positions[disk_id].1, &sim_box),
+            },
+            radius: radii[disk_id],
+            species: species[disk_id] as u32,
+            cell_id: 0,
+            displacement: geometry::Position { x: 0.0, y: 0.0 },
+        });
+    }
+    state::State::from_disks(disks, sim_box)
+}
+
+// A, B, C... like HOOMD
+fn type_name(species: u32) -> String {
+    if species < 26 {
+        return char::from(b'A' + species as u8).to_string();
+    }
+    format!("S{}", species)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gsd_round_trip() {
+        let mut state = state::State::hexagonal_packing(4, 6, 0.6);
+        state.disks[3].species = 1;
+        state.disks[3].radius *= 0.9;
+        let filepath = std::env::temp_dir().join("hard-disks-test.gsd");
+        if filepath.exists() {
+            std::fs::remove_file(&filepath).unwrap();
+        }
+        // Enough frames to move the index
+        let mut gsd_file = GsdFile::create(&filepath);
+        for step in 0..100 {
+            state.update_disk_coordinates(
+                0,
+                state.disks[0].position.x + 0.001,
+                state.disks[0].position.y,
+            );
+            append_frame(
+                &mut gsd_file,
+                &state,
+                step,
+                &[("cell_id", 1, ChunkData::UInt32(vec![0; 24]))],
+            );
+        }
+        let first_frame = read_state(&filepath, Some(0));
+        let last_frame = read_state(&filepath, None);
+        let mut gsd_file = GsdFile::open(&filepath);
+        assert_eq!(gsd_file.number_of_frames(), 100);
+        assert_eq!(
+            gsd_file.read_chunk(99, "configuration/step"),
+            Some(ChunkData::UInt64(vec![99]))
+        );
+        assert_eq!(
+            gsd_file.read_chunk(5, "log/particles/cell_id"),
+            Some(ChunkData::UInt32(vec![0; 24]))
+        );
+        std::fs::remove_file(&filepath).unwrap();
+
+        assert_eq!(last_frame.disks.len(), 24);
+        assert_eq!(last_frame.sim_box.lx, state.sim_box.lx);
+        assert!((first_frame.disks[0].position.x - last_frame.disks[0].position.x).abs() > 0.09);
+        for i in 0..state.disks.len() {
+            assert_eq!(last_frame.disks[i].position.x, state.disks[i].position.x);
+            assert_eq!(last_frame.disks[i].position.y, state.disks[i].position.y);
+            assert_eq!(last_frame.disks[i].radius, state.disks[i].radius);
+            assert_eq!(last_frame.disks[i].species, state.disks[i].species);
+        }
+    }
+
+    #[test]
+    fn test_file_layout() {
+        // Offsets of the gsd 2.0 specification, as the gsd library reads them
+        let state = state::State::hexagonal_packing(4, 4, 0.6);
+        let filepath = std::env::temp_dir().join("hard-disks-test-layout.gsd");
+        let mut gsd_file = GsdFile::create(&filepath);
+        append_frame(&mut gsd_file, &state, 7, &[]);
+        let bytes = std::fs::read(&filepath).unwrap();
+        std::fs::remove_file(&filepath).unwrap();
+        let read_u64 =
+            |offset: usize| u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap());
+        let read_u32 =
+            |offset: usize| u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap());
+
+        // Header: magic, index and namelist blocks, schema and gsd versions,
+        // then the null padded application and schema names
+        assert_eq!(read_u64(0), 0x65DF65DF65DF65DF);
+        let index_location = read_u64(8) as usize;
+        assert_eq!(index_location, 256 + 64 * 64);
+        assert_eq!(read_u64(16), 128);
+        assert_eq!(read_u64(24), 256);
+        assert_eq!(read_u64(32), 64);
+        assert_eq!(read_u32(40), 0x0001_0004);
+        assert_eq!(read_u32(44), 0x0002_0000);
+        assert_eq!(&bytes[48..59], b"hard-disks\0");
+        assert_eq!(&bytes[112..118], b"hoomd\0");
+
+        // Null terminated names one after the other, ended by an empty one
+        let names = [
+            "configuration/step",
+            "configuration/dimensions",
+            "configuration/box",
+            "particles/N",
+            "particles/types",
+            "particles/typeid",
+            "particles/position",
+            "particles/diameter",
+            "log/hard_disks/box",
+            "log/particles/hard_disks_position",
+            "log/particles/hard_disks_radius",
+        ];
+        let namelist = names.join("\0") + "\0\0";
+        assert_eq!(&bytes[256..256 + namelist.len()], namelist.as_bytes());
+
+        // Index entries of 32 bytes: frame, N, location, M, id, type, flags
+        for (id, _) in names.iter().enumerate() {
+            let entry = index_location + 32 * id;
+            assert_eq!(read_u64(entry), 0);
+            assert_eq!(bytes[entry + 28..entry + 30], (id as u16).to_le_bytes());
+            assert_eq!(bytes[entry + 31], 0);
+        }
+        let step_entry = index_location;
+        assert_eq!(read_u64(step_entry + 8), 1);
+        assert_eq!(read_u32(step_entry + 24), 1);
+        assert_eq!(bytes[step_entry + 30], 4);
+        assert_eq!(read_u64(read_u64(step_entry + 16) as usize), 7);
+        let dimensions_entry = index_location + 32;
+        assert_eq!(bytes[dimensions_entry + 30], 1);
+        assert_eq!(bytes[read_u64(dimensions_entry + 16) as usize], 2);
+        // Box as Lx, Ly, Lz, xy, xz, yz, Lz = 0 for a 2D box
+        let box_entry = index_location + 2 * 32;
+        assert_eq!(read_u64(box_entry + 8), 6);
+        assert_eq!(bytes[box_entry + 30], 9);
+        let box_location = read_u64(box_entry + 16) as usize;
+        let box_values: Vec<f32> = bytes[box_location..box_location + 24]
+            .chunks_exact(4)
+            .map(|b| f32::from_le_bytes(b.try_into().unwrap()))
+            .collect();
+        assert_eq!(
+            box_values,
+            vec![
+                state.sim_box.lx as f32,
+                state.sim_box.ly as f32,
+                0.0,
+                0.0,
+                0.0,
+                0.0
+            ]
+        );
+        let position_entry = index_location + 6 * 32;
+        assert_eq!(read_u64(position_entry + 8), 16);
+        assert_eq!(read_u32(position_entry + 24), 3);
+        // Unused entries are zero
+        assert_eq!(read_u64(index_location + 32 * names.len() + 16), 0);
+    }
+}
@@ -2,11 +2,23 @@ use std::env;
 use std::path;
 
 pub mod config;
+pub mod defects;
 pub mod disks;
+pub mod dynamics;
 pub mod geometry;
+pub mod gsd;
+pub mod order;
+pub mod replica_exchange;
 pub mod sample;
+pub mod simulation;
 pub mod state;
+pub mod stats;
+pub mod structure_factor;
 pub mod thermo;
+pub mod time_series;
+pub mod trajectory;
+pub mod voronoi;
+pub mod widom;
 
 fn main() {
     let args: Vec<String> = env::args().collect();
@@ -22,29 +34,99 @@ fn main() {
 
     let config = config::Config::from_yaml_file(file_path.as_str());
 
-    let disk_each_direction = (config.n_disk as f64).sqrt().floor() as u32;
-    if config.n_disk != disk_each_direction * disk_each_direction {
-        panic!("Number of disk must be a perfect square");
+    if config.replica_exchange.is_some() && config.restart_file.is_some() {
+        panic!("Replica-exchange runs can’t be restarted");
     }
-    let mut state = state::State::hexagonal_packing(
-        disk_each_direction,
-        disk_each_direction,
-        config.packing_fraction,
-    );
-
-    let filepath = path::Path::new("initial.txt");
-    state.write_coords_to_file(filepath);
-
-    let thermo: thermo::Thermo;
-    if let Some(pressure) = config.pressure {
-        thermo = sample::sample_npt(&mut state, pressure, config.n_step);
+
+    let mut simulation: simulation::Simulation;
+    if let Some(restart_file) = &config.restart_file {
+        simulation = simulation::Simulation::from_checkpoint(path::Path::new(restart_file));
+    } else {
+        let (mut rng, seed) = sample::create_rng(config.seed);
+        let state: state::State;
+        if let Some(initial_configuration) = &config.initial_configuration {
+            let filepath = path::Path::new(initial_configuration);
+            // Last frame of a gsd trajectory, or a file from write_coords_to_file
+            if filepath
+                .extension()
+                .is_some_and(|extension| extension == "gsd")
+            {
+                state = gsd::read_state(filepath, None);
+            } else {
+                state = state::State::from_coords_file(filepath);
+            }
+            if state.disks.len() != config.n_disk as usize {
+                panic!(
+                    "{} contains {} disks, but n_disk is {}",
+                    initial_configuration,
+                    state.disks.len(),
+                    config.n_disk
+                );
+            }
+        } else {
+            let disk_each_direction = (config.n_disk as f64).sqrt().floor() as u32;
+            if config.n_disk != disk_each_direction * disk_each_direction {
+                panic!("Number of disk must be a perfect square");
+            }
+            let (radii, species) =
+                disks::draw_sizes(&config.composition, config.n_disk as usize, &mut rng);
+            state = state::State::hexagonal_packing_with_sizes(
+                disk_each_direction,
+                disk_each_direction,
+                config.packing_fraction,
+                &radii,
+                &species,
+            );
+        }
+
+        let filepath = path::Path::new("initial.txt");
+        state.write_coords_to_file(filepath);
+
+        if config.replica_exchange.is_some() {
+            let mut replica_exchange =
+                replica_exchange::ReplicaExchange::new(state, rng, seed, &config);
+            replica_exchange.run(&config);
+            for (index, replica) in replica_exchange.replicas.iter().enumerate() {
+                let filepath = format!("final-{}.txt", index);
+                replica
+                    .state
+                    .write_coords_to_file(path::Path::new(&filepath));
+            }
+            let filepath = path::Path::new("results.yaml");
+            replica_exchange.into_results().to_yaml(filepath);
+            return;
+        }
+
+        simulation = simulation::Simulation::new(state, rng, seed, &config);
+    }
+
+    if let Some(activity) = config.activity {
+        if config.pressure.is_some() {
+            panic!("pressure and activity can’t be used together");
+        }
+        if config.algorithm == config::Algorithm::EventChain {
+            panic!("Event-chain moves are only available for NVT runs");
+        }
+        sample::sample_muvt(&mut simulation, activity, &config);
+    } else if let Some(pressure) = config.pressure {
+        if config.algorithm == config::Algorithm::EventChain {
+            panic!("Event-chain moves are only available for NVT runs");
+        }
+        sample::sample_npt(&mut simulation, pressure, &config);
     } else {
-        thermo = sample::sample_nvt(&mut state, config.n_step);
+        match config.algorithm {
+            config::Algorithm::Metropolis => {
+                sample::sample_nvt(&mut simulation, &config);
+            }
+            config::Algorithm::EventChain => {
+                sample::sample_ecmc(&mut simulation, &config);
+            }
+        }
     }
 
     let filepath = path::Path::new("results.yaml");
-    thermo.to_yaml(filepath);
+    simulation.thermo.to_yaml(filepath);
 
     let filepath = path::Path::new("final.txt");
-    state.write_coords_to_file(filepath);
+    simulation.state.write_coords_to_file(filepath);
 }
@@ -0,0 +1,242 @@
+use serde::{Deserialize, Serialize};
+
+use crate::config;
+use crate::geometry;
+use crate::state;
+use crate::stats;
+use crate::voronoi;
+
+// Bond-orientational order, ψ6_j = 1/n_j Σ_k exp(6 i θ_jk) over the neighbors k of j
+#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+pub struct Complex {
+    pub re: f64,
+    pub im: f64,
+}
+
+impl Complex {
+    pub fn norm(&self) -> f64 {
+        (self.re * self.re + self.im * self.im).sqrt()
+    }
+}
+
+// Time series of the global order parameter |Ψ6|
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Psi6Series {
+    pub neighbors: config::NeighborMethod,
+    // Disks closer than this are neighbors, unused for Voronoi neighbors
+    pub cutoff: f64,
+    pub step: Vec<u32>,
+    pub psi6: Vec<f64>,
+}
+
+impl Psi6Series {
+    pub fn new(neighbors: config::NeighborMethod, cutoff: f64) -> Psi6Series {
+        Psi6Series {
+            neighbors,
+            cutoff,
+            step: Vec::new(),
+            psi6: Vec::new(),
+        }
+    }
+
+    pub fn update(&mut self, state: &state::State, step: u32) {
+        let neighbors = find_neighbors(state, self.neighbors, self.cutoff);
+        self.step.push(step);
+        self.psi6.push(global_psi6(&local_psi6(state, &neighbors)));
+    }
+}
+
+// g6(r) = <ψ6_j* ψ6_k> over pairs at distance r
+#[derive(Debug, Serialize, Deserialize)]
+pub struct G6ofR {
+    // Neighbors used for the local ψ6
+    pub neighbors: config::NeighborMethod,
+    pub cutoff: f64,
+    pub r: Vec<f64>,
+    pub g6: Vec<f64>,
+    r_max: f64,
+    dr: f64,
+    counter: u32,
+    // Sums of the correlation for each bin, then the pair counts
+    sums: stats::BlockSums,
+}
+
+impl G6ofR {
+    pub fn new(
+        neighbors: config::NeighborMethod,
+        cutoff: f64,
+        r_max: f64,
+        bin_width: f64,
+    ) -> G6ofR {
+        let n_points = (r_max / bin_width).floor() as usize;
+        let mut r: Vec<f64> = Vec::new();
+        for i in 0..n_points {
+            r.push((i as f64 + 0.5) * bin_width);
+        }
+        G6ofR {
+            neighbors,
+            cutoff,
+            r,
+            g6: vec![0.0; n_points],
+            r_max: n_points as f64 * bin_width,
+            dr: bin_width,
+            counter: 0,
+            sums: stats::BlockSums::new(2 * n_points),
+        }
+    }
+
+    pub fn update(&mut self, state: &state::State) {
+        let neighbors = find_neighbors(state, self.neighbors, self.cutoff);
+        let psi6 = local_psi6(state, &neighbors);
+        // Beyond half the box, some pairs would be missed
+        let r_max = self.r_max.min(state.sim_box.lx.min(state.sim_box.ly) / 2.0);
+        let r_max_sq = r_max * r_max;
+        let n_points = self.r.len();
+        let mut values = vec![0.0; 2 * n_points];
+        for i in 0..state.disks.len() {
+            for j in i + 1..state.disks.len() {
+                let r_sq = geometry::distance_sq_periodic(
+                    &state.disks[i].position,
+                    &state.disks[j].position,
+                    &state.sim_box,
+                );
+                if r_sq < r_max_sq {
+                    let bin = (r_sq.sqrt() / self.dr).floor() as usize;
+                    // Real part of ψ6_i* ψ6_j, the imaginary part averages out
+                    values[bin] += psi6[i].re * psi6[j].re + psi6[i].im * psi6[j].im;
+                    values[n_points + bin] += 1.0;
+                }
+            }
+        }
+        self.sums.add(self.counter as usize, &values);
+        self.counter += 1;
+    }
+
+    pub fn discard_samples(&mut self, n_samples: usize) {
+        self.sums.discard_samples(n_samples);
+    }
+
+    pub fn finalize(&mut self) {
+        let sums = self.sums.total();
+        let n_points = self.r.len();
+        for k in 0..n_points {
+            if sums[n_points + k] > 0.0 {
+                self.g6[k] = sums[k] / sums[n_points + k];
+            }
+        }
+    }
+}
+
+pub fn find_neighbors(
+    state: &state::State,
+    method: config::NeighborMethod,
+    cutoff: f64,
+) -> Vec<Vec<usize>> {
+    match method {
+        config::NeighborMethod::Cutoff => cutoff_neighbors(state, cutoff),
+        config::NeighborMethod::Voronoi => voronoi_neighbors(state),
+    }
+}
+
+pub fn local_psi6(state: &state::State, neighbors: &[Vec<usize>]) -> Vec<Complex> {
+    let mut psi6: Vec<Complex> = Vec::new();
+    for (disk_id, disk_neighbors) in neighbors.iter().enumerate() {
+        let mut value = Complex { re: 0.0, im: 0.0 };
+        for neighbor_id in disk_neighbors.iter() {
+            let mut dx = state.disks[*neighbor_id].position.x - state.disks[disk_id].position.x;
+            let mut dy = state.disks[*neighbor_id].position.y - state.disks[disk_id].position.y;
+            geometry::apply_boundary_conditions(&mut dx, &mut dy, &state.sim_box);
+            let angle = 6.0 * dy.atan2(dx);
+            value.re += angle.cos();
+            value.im += angle.sin();
+        }
+        // Isolated disks have no order
+        if !disk_neighbors.is_empty() {
+            value.re /= disk_neighbors.len() as f64;
+            value.im /= disk_neighbors.len() as f64;
+        }
+        psi6.push(value);
+    }
+    psi6
+}
+
+// |Ψ6| = |1/N Σ_j ψ6_j|
+pub fn global_psi6(local_psi6: &[Complex]) -> f64 {
+    let mut sum = Complex { re: 0.0, im: 0.0 };
+    for value in local_psi6.iter() {
+        sum.re += value.re;
+        sum.im += value.im;
+    }
+    sum.re /= local_psi6.len() as f64;
+    sum.im /= local_psi6.len() as f64;
+    sum.norm()
+}
+
+pub fn cutoff_neighbors(state: &state::State, cutoff: f64) -> Vec<Vec<usize>> {
+    if state.get_cell_size() >= cutoff {
+        return (0..state.disks.len())
+            .map(|disk_id| state.get_neighbors_within(disk_id, cutoff))
+            .collect();
+    }
+    // The cells of the sampler are too small, e.g. after the box shrank in
+    // NPT runs. Search on a copy with larger cells, the sampler keeps its own.
+    let mut analysis_state = state.clone();
+    analysis_state.set_min_cell_size(cutoff);
+    (0..analysis_state.disks.len())
+        .map(|disk_id| analysis_state.get_neighbors_within(disk_id, cutoff))
+        .collect()
+}
+
+// Disks sharing an edge, each counted once even in tiny boxes
+pub fn voronoi_neighbors(state: &state::State) -> Vec<Vec<usize>> {
+    let mut neighbors: Vec<Vec<usize>> = Vec::new();
+    for (disk_id, cell) in voronoi::tessellate(state).iter().enumerate() {
+        let mut disk_neighbors: Vec<usize> = cell
+            .neighbors
+            .iter()
+            .copied()
+            .filter(|id| *id != disk_id)
+            .collect();
+        disk_neighbors.sort();
+        disk_neighbors.dedup();
+        neighbors.push(disk_neighbors);
+    }
+    neighbors
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_perfect_lattice() {
+        let state = state::State::hexagonal_packing(8, 8, 0.7);
+        let lattice_spacing = state.sim_box.lx / 8.0;
+        let cell_size = state.get_cell_size();
+        assert!(cell_size < 1.2 * lattice_spacing);
+        let neighbors = cutoff_neighbors(&state, 1.2 * lattice_spacing);
+        // The analysis leaves the cells of the sampler alone
+        assert_eq!(state.get_cell_size(), cell_size);
+        for disk_neighbors in neighbors.iter() {
+            assert_eq!(disk_neighbors.len(), 6);
+        }
+        let psi6 = local_psi6(&state, &neighbors);
+        assert!((global_psi6(&psi6) - 1.0).abs() < 1e-10);
+
+        // Every pair is perfectly correlated on the lattice
+        let mut g6_of_r = G6ofR::new(
+            config::NeighborMethod::Voronoi,
+            1.2 * lattice_spacing,
+            state.sim_box.ly / 2.0,
+            0.1,
+        );
+        g6_of_r.update(&state);
+        g6_of_r.finalize();
+        let pair_counts = g6_of_r.sums.total().split_off(g6_of_r.r.len());
+        for (k, pair_count) in pair_counts.iter().enumerate() {
+            if *pair_count > 0.0 {
+                assert!((g6_of_r.g6[k] - 1.0).abs() < 1e-10);
+            }
+        }
+    }
+}
@@ -0,0 +1,274 @@
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::path::Path;
+
+use crate::config;
+use crate::sample;
+use crate::simulation;
+use crate::state;
+use crate::thermo;
+
+// Parallel tempering in pressure: one NPT replica per βP, each in its own
+// thread. Every swap_interval sweeps, configurations at neighboring pressures
+// are exchanged, so a replica stuck in one phase can escape through the
+// others.
+pub struct ReplicaExchange {
+    pub pressures: Vec<f64>,
+    pub replicas: Vec<simulation::Simulation>,
+    swap_interval: u32,
+    // Only draws the swap acceptances, the replicas have their own streams
+    rng: sample::SimulationRng,
+    seed: u64,
+    // Initial configuration now at each pressure, to follow the walks
+    configuration_ids: Vec<usize>,
+    // Between pressures i and i + 1
+    nb_swap_trials: Vec<u64>,
+    nb_swap_success: Vec<u64>,
+    // Even rounds try the pairs (0, 1), (2, 3)..., odd ones (1, 2), (3, 4)...
+    round: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReplicaExchangeResults {
+    pub seed: u64,
+    pub pressures: Vec<f64>,
+    pub swap_interval: u32,
+    // Between pressures i and i + 1
+    pub swap_acceptance_rates: Vec<f64>,
+    pub configuration_ids: Vec<usize>,
+    // One per pressure, in the same order
+    pub replicas: Vec<thermo::Thermo>,
+}
+
+impl ReplicaExchange {
+    pub fn new(
+        state: state::State,
+        rng: sample::SimulationRng,
+        seed: u64,
+        config: &config::Config,
+    ) -> ReplicaExchange {
+        let replica_config = config
+            .replica_exchange
+            .as_ref()
+            .expect("No replica_exchange in the config.");
+        if config.pressure.is_some() {
+            panic!("pressure and replica_exchange can’t be used together");
+        }
+        if config.activity.is_some() {
+            panic!("activity and replica_exchange can’t be used together");
+        }
+        // The replicas only write results.yaml and their final configurations
+        if config.trajectory.is_some() || config.time_series.is_some() {
+            panic!("Replica-exchange runs don’t write trajectories or time series");
+        }
+        if config.checkpoint_interval.is_some() {
+            panic!("Replica-exchange runs can’t be checkpointed");
+        }
+        if config.algorithm == config::Algorithm::EventChain {
+            panic!("Event-chain moves are only available for NVT runs");
+        }
+        if config.dynamics.is_some() {
+            panic!("Dynamics are meaningless across replica swaps");
+        }
+        let nb_pressures = replica_config.pressures.len();
+        let mut replicas = Vec::new();
+        let mut replica_rng = rng.clone();
+        for pressure in replica_config.pressures.iter() {
+            // Streams 2^128 draws apart, so the replicas are independent
+            replica_rng.jump();
+            let mut simulation =
+                simulation::Simulation::new(state.clone(), replica_rng.clone(), seed, config);
+            simulation.volume.max_volume_change =
+                config.max_volume_change.unwrap_or(2.0 / pressure);
+            replicas.push(simulation);
+        }
+        ReplicaExchange {
+            pressures: replica_config.pressures.clone(),
+            replicas,
+            swap_interval: replica_config.swap_interval,
+            rng,
+            seed,
+            configuration_ids: (0..nb_pressures).collect(),
+            nb_swap_trials: vec![0; nb_pressures.saturating_sub(1)],
+            nb_swap_success: vec![0; nb_pressures.saturating_sub(1)],
+            round: 0,
+        }
+    }
+
+    pub fn run(&mut self, config: &config::Config) {
+        let nb_disks = self.replicas[0].state.disks.len() as u32;
+        let nb_equilibration_steps = config.n_equilibration_steps.unwrap_or(0);
+        let nb_steps = config.n_production_steps;
+        let number_of_sweeps = nb_steps.div_ceil(nb_disks);
+        for simulation in self.replicas.iter_mut() {
+            simulation
+                .thermo
+                .initialize_observables(config, &simulation.state, &simulation.rng);
+        }
+
+        // No swaps yet, each replica tunes its move sizes at its own pressure
+        self.run_in_parallel(|simulation, pressure| {
+            sample::equilibrate_npt(
+                simulation,
+                pressure,
+                nb_equilibration_steps,
+                &mut simulation::Checkpointer::disabled(),
+                &mut None,
+            );
+        });
+
+        let mut sweep_id = 0;
+        while sweep_id < number_of_sweeps {
+            let nb_sweeps = self.swap_interval.min(number_of_sweeps - sweep_id);
+            self.run_in_parallel(|simulation, pressure| {
+                for _ in 0..nb_sweeps {
+                    sample::npt_sweep(simulation, pressure, nb_equilibration_steps, nb_steps);
+                }
+            });
+            sweep_id += nb_sweeps;
+            self.attempt_swaps();
+        }
+
+        for simulation in self.replicas.iter_mut() {
+            sample::finalize_npt(simulation, config);
+        }
+    }
+
+    fn run_in_parallel<F>(&mut self, work: F)
+    where
+        F: Fn(&mut simulation::Simulation, f64) + Sync,
+    {
+        let work = &work;
+        std::thread::scope(|scope| {
+            for (simulation, pressure) in self.replicas.iter_mut().zip(self.pressures.iter()) {
+                scope.spawn(move || work(simulation, *pressure));
+            }
+        });
+    }
+
+    // Configurations at βP_i and βP_j are exchanged with probability
+    // min(1, exp((βP_i - βP_j)(V_i - V_j)))
+    fn attempt_swaps(&mut self) {
+        let first = (self.round % 2) as usize;
+        for i in (first..self.pressures.len().saturating_sub(1)).step_by(2) {
+            let j = i + 1;
+            let volume_i = self.replicas[i].state.sim_box.lx * self.replicas[i].state.sim_box.ly;
+            let volume_j = self.replicas[j].state.sim_box.lx * self.replicas[j].state.sim_box.ly;
+            let exponent = (self.pressures[i] - self.pressures[j]) * (volume_i - volume_j);
+            self.nb_swap_trials[i] += 1;
+            if exponent.exp() > self.rng.gen::<f64>() {
+                let (left, right) = self.replicas.split_at_mut(j);
+                std::mem::swap(&mut left[i].state, &mut right[0].state);
+                self.configuration_ids.swap(i, j);
+                self.nb_swap_success[i] += 1;
+            }
+        }
+        self.round += 1;
+    }
+
+    pub fn into_results(self) -> ReplicaExchangeResults {
+        ReplicaExchangeResults {
+            seed: self.seed,
+            pressures: self.pressures,
+            swap_interval: self.swap_interval,
+            swap_acceptance_rates: self
+                .nb_swap_success
+                .iter()
+                .zip(self.nb_swap_trials.iter())
+                .map(|(success, trials)| *success as f64 / *trials as f64)
+                .collect(),
+            configuration_ids: self.configuration_ids,
+            replicas: self
+                .replicas
+                .into_iter()
+                .map(|simulation| simulation.thermo)
+                .collect(),
+        }
+    }
+}
+
+impl ReplicaExchangeResults {
+    pub fn to_yaml(&self, filepath: &Path) {
+        let file = File::create(filepath).unwrap();
+        serde_yaml::to_writer(file, &self).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_swaps() {
+        let config: config::Config = serde_yaml::from_str(
+            "{n_disk: 16, packing_fraction: 0.5, n_production_steps: 3200, seed: 5, \
+            replica_exchange: {pressures: [4.0, 4.0, 4.0], swap_interval: 10}}",
+        )
+        .unwrap();
+        let mut final_positions: Vec<Vec<(f64, f64)>> = Vec::new();
+        for _ in 0..2 {
+            let state = sample::test_state(&config);
+            let (rng, seed) = sample::create_rng(config.seed);
+            let mut replica_exchange = ReplicaExchange::new(state, rng, seed, &config);
+            replica_exchange.run(&config);
+            final_positions.push(
+                replica_exchange.replicas[2]
+                    .state
+                    .disks
+                    .iter()
+                    .map(|disk| (disk.position.x, disk.position.y))
+                    .collect(),
+            );
+
+            let results = replica_exchange.into_results();
+            // Equal pressures, every swap is accepted
+            assert_eq!(results.swap_acceptance_rates, vec![1.0, 1.0]);
+            // The walks come back every 6 rounds, 20 rounds are 2 more
+            assert_eq!(results.configuration_ids, vec![1, 2, 0]);
+            assert_eq!(results.replicas.len(), 3);
+        }
+        // Same seed, same run whatever the thread scheduling
+        assert_eq!(final_positions[0], final_positions[1]);
+    }
+
+    #[test]
+    #[should_panic(expected = "don’t write trajectories")]
+    fn test_trajectory_rejected() {
+        let config: config::Config = serde_yaml::from_str(
+            "{n_disk: 16, packing_fraction: 0.5, n_production_steps: 160, \
+            trajectory: {stride: 16, file: out.xyz}, \
+            replica_exchange: {pressures: [4.0, 5.0], swap_interval: 10}}",
+        )
+        .unwrap();
+        let state = sample::test_state(&config);
+        let (rng, seed) = sample::create_rng(config.seed);
+        ReplicaExchange::new(state, rng, seed, &config);
+    }
+
+    #[test]
+    fn test_widom_streams_differ() {
+        let config: config::Config = serde_yaml::from_str(
+            "{n_disk: 16, packing_fraction: 0.1, n_production_steps: 1600, seed: 5, \
+            widom: {insertions: 1000}, \
+            replica_exchange: {pressures: [2.0, 2.0], swap_interval: 10}}",
+        )
+        .unwrap();
+        let state = sample::test_state(&config);
+        let (rng, seed) = sample::create_rng(config.seed);
+        let mut replica_exchange = ReplicaExchange::new(state.clone(), rng, seed, &config);
+        // Ghosts dropped in the same configuration land elsewhere
+        let mut probabilities: Vec<Vec<f64>> = Vec::new();
+        for simulation in replica_exchange.replicas.iter_mut() {
+            simulation
+                .thermo
+                .initialize_observables(&config, &state, &simulation.rng);
+            let widom = simulation.thermo.widom.as_mut().unwrap();
+            for step in 0..5 {
+                widom.update(&state, step);
+            }
+            probabilities.push(widom.insertion_probability.clone());
+        }
+        assert_ne!(probabilities[0], probabilities[1]);
+    }
+}
@@ -1,22 +1,215 @@
-use rand;
 use rand::Rng;
+use rand::SeedableRng;
+use serde::{Deserialize, Serialize};
 
+use crate::config;
+use crate::disks;
+use crate::geometry;
+use crate::simulation;
 use crate::state;
 use crate::thermo;
+use crate::time_series;
+use crate::trajectory;
+use std::f64::consts::PI;
 
-pub fn sample_nvt(state: &mut state::State, nb_steps: u32) -> thermo::Thermo {
-    let mut thermo = thermo::Thermo::empty_thermo();
-    thermo.g_of_r.initialize_vectors(state.disks[0].radius);
+pub type SimulationRng = rand_xoshiro::Xoshiro256PlusPlus;
 
-    let max_displacement = 0.05;
-    let mut rng = rand::thread_rng();
+// Without a seed in the config, one is drawn from the system entropy
+pub fn create_rng(seed: Option<u64>) -> (SimulationRng, u64) {
+    let seed = seed.unwrap_or_else(|| rand::thread_rng().gen());
+    (SimulationRng::seed_from_u64(seed), seed)
+}
+
+// Number of blocks the g(r) updates are split into for the error bars
+const NUMBER_OF_BLOCKS: u32 = 50;
+
+#[derive(Serialize, Deserialize)]
+pub struct DisplacementMove {
+    pub max_displacement: f64,
+    pub shape: config::MoveShape,
+    pub target_acceptance: f64,
+}
+
+impl DisplacementMove {
+    pub fn from_config(config: &config::Config) -> DisplacementMove {
+        DisplacementMove {
+            max_displacement: config.max_displacement.unwrap_or(0.05),
+            shape: config.move_shape,
+            target_acceptance: config.target_acceptance.unwrap_or(0.5),
+        }
+    }
+
+    pub fn propose<R: Rng>(&self, rng: &mut R) -> (f64, f64) {
+        match self.shape {
+            config::MoveShape::Square => {
+                let dx = (2.0 * rng.gen::<f64>() - 1.0) * self.max_displacement;
+                let dy = (2.0 * rng.gen::<f64>() - 1.0) * self.max_displacement;
+                (dx, dy)
+            }
+            config::MoveShape::Disk => {
+                let length = self.max_displacement * rng.gen::<f64>().sqrt();
+                let angle = 2.0 * PI * rng.gen::<f64>();
+                (length * angle.cos(), length * angle.sin())
+            }
+        }
+    }
+
+    // Scale the step size towards the target acceptance ratio
+    pub fn tune(&mut self, acceptance: f64, sim_box: &geometry::Box) {
+        let factor = (acceptance / self.target_acceptance).clamp(0.5, 2.0);
+        self.max_displacement =
+            (self.max_displacement * factor).min(sim_box.lx.min(sim_box.ly) / 2.0);
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct VolumeMove {
+    pub max_volume_change: f64,
+    pub target_acceptance: f64,
+}
+
+impl VolumeMove {
+    pub fn from_config(config: &config::Config) -> VolumeMove {
+        let default_volume_change = config.pressure.map_or(1.0, |pressure| 2.0 / pressure);
+        VolumeMove {
+            max_volume_change: config.max_volume_change.unwrap_or(default_volume_change),
+            target_acceptance: config.target_volume_acceptance.unwrap_or(0.3),
+        }
+    }
+
+    // Same as the step size, the box can at most shrink by half in one move
+    pub fn tune(&mut self, acceptance: f64, sim_box: &geometry::Box) {
+        let factor = (acceptance / self.target_acceptance).clamp(0.5, 2.0);
+        self.max_volume_change =
+            (self.max_volume_change * factor).min(sim_box.lx * sim_box.ly / 2.0);
+    }
+}
+
+pub fn sample_nvt(simulation: &mut simulation::Simulation, config: &config::Config) {
+    let nb_equilibration_steps = config.n_equilibration_steps.unwrap_or(0);
+    let nb_steps = config.n_production_steps;
+    let nb_disks = simulation.state.disks.len() as u32;
+    let number_steps_between_updates = 100 * nb_disks;
+    if simulation.step == 0 {
+        let number_of_updates = nb_steps.div_ceil(number_steps_between_updates);
+        simulation.thermo.initialize_g_of_r(
+            &simulation.state,
+            number_of_updates.div_ceil(NUMBER_OF_BLOCKS),
+        );
+        simulation
+            .thermo
+            .initialize_observables(config, &simulation.state, &simulation.rng);
+    }
+    let mut checkpointer = simulation::Checkpointer::from_config(config, simulation.step);
+    let mut trajectory = trajectory::TrajectoryWriter::from_config(config, simulation);
+    let mut time_series = time_series::TimeSeriesWriter::from_config(config, simulation);
+
+    equilibrate_displacement(
+        simulation,
+        nb_equilibration_steps,
+        &mut checkpointer,
+        &mut time_series,
+    );
+
+    while simulation.step < nb_equilibration_steps + nb_steps {
+        let production_step = simulation.step - nb_equilibration_steps;
+        if production_step.is_multiple_of(number_steps_between_updates) {
+            simulation.thermo.update_g_of_r(&simulation.state);
+            simulation
+                .thermo
+                .sample_observables(&mut simulation.state, production_step);
+        }
+        let mut nb_steps_to_do = (number_steps_between_updates
+            - production_step % number_steps_between_updates)
+            .min(nb_steps - production_step);
+        if let Some(time_series) = &time_series {
+            nb_steps_to_do = nb_steps_to_do.min(time_series.steps_to_next_row(simulation.step));
+        }
+        let nb_success = displacement_moves(
+            &mut simulation.state,
+            nb_steps_to_do,
+            &simulation.displacement,
+            &mut simulation.rng,
+        );
+        simulation.nb_displacement_trials += nb_steps_to_do as u64;
+        simulation.nb_displacement_success += nb_success as u64;
+        simulation.step += nb_steps_to_do;
+        // Outputs first, so the checkpoint knows how far they were written
+        if let Some(trajectory) = &mut trajectory {
+            trajectory.write_if_needed(simulation);
+        }
+        if let Some(time_series) = &mut time_series {
+            time_series.write_if_needed(simulation);
+        }
+        checkpointer.save_if_needed(simulation);
+    }
+    checkpointer.save(simulation);
+
+    let thermo = &mut simulation.thermo;
+    thermo.nvt_acceptance_rate =
+        simulation.nb_displacement_success as f64 / simulation.nb_displacement_trials as f64;
+    thermo.max_displacement = simulation.displacement.max_displacement;
+    // Separate stream for the bootstrap, so a restarted run stays identical
+    let mut bootstrap_rng = simulation.rng.clone();
+    thermo.detect_equilibration(&simulation.state, config.contact_fit);
+    thermo.finalize_g_of_r(&simulation.state, config.contact_fit, &mut bootstrap_rng);
+    thermo.finalize_observables();
+}
+
+// Tune the step size every 10 sweeps during the equilibration, it is frozen
+// afterwards. The last window is shorter when the equilibration is not a
+// whole number of them.
+fn equilibrate_displacement(
+    simulation: &mut simulation::Simulation,
+    nb_equilibration_steps: u32,
+    checkpointer: &mut simulation::Checkpointer,
+    time_series: &mut Option<time_series::TimeSeriesWriter>,
+) {
+    let number_steps_between_tuning = 10 * simulation.state.disks.len() as u32;
+    while simulation.step < nb_equilibration_steps {
+        let mut nb_steps_to_do = (number_steps_between_tuning
+            - simulation.step % number_steps_between_tuning)
+            .min(nb_equilibration_steps - simulation.step);
+        if let Some(time_series) = time_series {
+            nb_steps_to_do = nb_steps_to_do.min(time_series.steps_to_next_row(simulation.step));
+        }
+        simulation.nb_tuning_displacement_success += displacement_moves(
+            &mut simulation.state,
+            nb_steps_to_do,
+            &simulation.displacement,
+            &mut simulation.rng,
+        ) as u64;
+        simulation.nb_tuning_displacement_trials += nb_steps_to_do as u64;
+        simulation.step += nb_steps_to_do;
+        if simulation.step.is_multiple_of(number_steps_between_tuning)
+            || simulation.step == nb_equilibration_steps
+        {
+            simulation.displacement.tune(
+                simulation.nb_tuning_displacement_success as f64
+                    / simulation.nb_tuning_displacement_trials as f64,
+                &simulation.state.sim_box,
+            );
+            simulation.nb_tuning_displacement_success = 0;
+            simulation.nb_tuning_displacement_trials = 0;
+        }
+        if let Some(time_series) = time_series {
+            time_series.write_if_needed(simulation);
+        }
+        checkpointer.save_if_needed(simulation);
+    }
+}
+
+// Single disk moves, returns the number of accepted ones
+fn displacement_moves<R: Rng>(
+    state: &mut state::State,
+    nb_steps: u32,
+    displacement: &DisplacementMove,
+    rng: &mut R,
+) -> u32 {
     let mut nb_success = 0;
     let nb_disks = state.disks.len() as u32;
-    let number_steps_between_updates = 100 * nb_disks;
-    for step_id in 0..nb_steps {
-        // Non isotropic
-        let dx: f64 = rng.gen::<f64>() * max_displacement;
-        let dy: f64 = rng.gen::<f64>() * max_displacement;
+    for _ in 0..nb_steps {
+        let (dx, dy) = displacement.propose(rng);
 
         let disk_index: usize = rng.gen_range(0..nb_disks).try_into().unwrap();
 
@@ -34,110 +227,771 @@ pub fn sample_nvt(state: &mut state::State, nb_steps: u32) -> thermo::Thermo {
         } else {
             nb_success += 1;
         }
+    }
+    nb_success
+}
+
+pub fn sample_ecmc(simulation: &mut simulation::Simulation, config: &config::Config) {
+    let nb_equilibration_chains = config.n_equilibration_steps.unwrap_or(0);
+    let nb_chains = config.n_production_steps;
+    let chain_length = config.chain_length.unwrap_or(simulation.state.sim_box.lx);
+    let nb_disks = simulation.state.disks.len() as u32;
+    let number_chains_between_updates = nb_disks;
+    if simulation.step == 0 {
+        let number_of_updates = nb_chains.div_ceil(number_chains_between_updates);
+        simulation.thermo.initialize_g_of_r(
+            &simulation.state,
+            number_of_updates.div_ceil(NUMBER_OF_BLOCKS),
+        );
+        simulation.pressure_blocks = thermo::BlockAverage::new(number_chains_between_updates);
+        simulation
+            .thermo
+            .initialize_observables(config, &simulation.state, &simulation.rng);
+    }
+    let mut checkpointer = simulation::Checkpointer::from_config(config, simulation.step);
+    let mut trajectory = trajectory::TrajectoryWriter::from_config(config, simulation);
+    let mut time_series = time_series::TimeSeriesWriter::from_config(config, simulation);
 
-        if step_id % number_steps_between_updates == 0 {
-            thermo.g_of_r.update(state);
+    // Nothing to tune, the chain length is fixed
+    while simulation.step < nb_equilibration_chains {
+        event_chain(&mut simulation.state, chain_length, &mut simulation.rng);
+        simulation.step += 1;
+        if let Some(time_series) = &mut time_series {
+            time_series.write_if_needed(simulation);
+        }
+        checkpointer.save_if_needed(simulation);
+    }
+
+    let number_density = simulation.state.get_number_density();
+    while simulation.step < nb_equilibration_chains + nb_chains {
+        let production_chain = simulation.step - nb_equilibration_chains;
+        let lifted_excess = event_chain(&mut simulation.state, chain_length, &mut simulation.rng);
+        // βP / ρ = 1 + <lifted excess> / chain length
+        simulation
+            .pressure_blocks
+            .add(number_density * (1.0 + lifted_excess / chain_length));
+
+        if production_chain.is_multiple_of(number_chains_between_updates) {
+            simulation.thermo.update_g_of_r(&simulation.state);
+            simulation
+                .thermo
+                .sample_observables(&mut simulation.state, production_chain);
+        }
+        simulation.step += 1;
+        if let Some(trajectory) = &mut trajectory {
+            trajectory.write_if_needed(simulation);
         }
+        if let Some(time_series) = &mut time_series {
+            time_series.write_if_needed(simulation);
+        }
+        checkpointer.save_if_needed(simulation);
     }
-    thermo.nvt_acceptance_rate = nb_success as f64 / nb_steps as f64;
-    thermo.g_of_r.renormalize(&state);
+    checkpointer.save(simulation);
+
+    let thermo = &mut simulation.thermo;
+    let mut bootstrap_rng = simulation.rng.clone();
+    thermo.pressure_block_means = simulation.pressure_blocks.block_means.clone();
+    thermo.detect_equilibration(&simulation.state, config.contact_fit);
+    thermo.finalize_g_of_r(&simulation.state, config.contact_fit, &mut bootstrap_rng);
+    // Also sets the pressure, from the lifted excess
+    thermo.finalize_observables();
+}
 
-    return thermo;
+// Move one straight chain of total length chain_length, returns the sum of
+// the center separations along the chain at each lift
+fn event_chain<R: Rng>(state: &mut state::State, chain_length: f64, rng: &mut R) -> f64 {
+    // Straight event chains, along +x or +y
+    let along_x: bool = rng.gen_bool(0.5);
+    let mut disk_index: usize = rng.gen_range(0..state.disks.len());
+
+    let mut lifted_excess = 0.0;
+    let mut remaining_length = chain_length;
+    while remaining_length > 0.0 {
+        let (distance, target) = state.next_collision(disk_index, along_x, remaining_length);
+
+        let mut new_x = state.disks[disk_index].position.x;
+        let mut new_y = state.disks[disk_index].position.y;
+        if along_x {
+            new_x += distance;
+        } else {
+            new_y += distance;
+        }
+        state.update_disk_coordinates(disk_index, new_x, new_y);
+        remaining_length -= distance;
+
+        // Lift: the disk that was hit carries on with the rest of the chain
+        if let Some(next_disk_index) = target {
+            let mut dx =
+                state.disks[next_disk_index].position.x - state.disks[disk_index].position.x;
+            let mut dy =
+                state.disks[next_disk_index].position.y - state.disks[disk_index].position.y;
+            geometry::apply_boundary_conditions(&mut dx, &mut dy, &state.sim_box);
+            lifted_excess += if along_x { dx } else { dy };
+            disk_index = next_disk_index;
+        }
+    }
+    lifted_excess
 }
 
 pub fn sample_npt(
-    state: &mut state::State,
+    simulation: &mut simulation::Simulation,
+    pressure_over_kt: f64,
+    config: &config::Config,
+) {
+    let nb_equilibration_steps = config.n_equilibration_steps.unwrap_or(0);
+    let nb_steps = config.n_production_steps;
+    if simulation.step == 0 {
+        simulation
+            .thermo
+            .initialize_observables(config, &simulation.state, &simulation.rng);
+    }
+    let mut checkpointer = simulation::Checkpointer::from_config(config, simulation.step);
+    let mut trajectory = trajectory::TrajectoryWriter::from_config(config, simulation);
+    let mut time_series = time_series::TimeSeriesWriter::from_config(config, simulation);
+
+    equilibrate_npt(
+        simulation,
+        pressure_over_kt,
+        nb_equilibration_steps,
+        &mut checkpointer,
+        &mut time_series,
+    );
+
+    while simulation.step < nb_equilibration_steps + nb_steps {
+        npt_sweep(
+            simulation,
+            pressure_over_kt,
+            nb_equilibration_steps,
+            nb_steps,
+        );
+        if let Some(trajectory) = &mut trajectory {
+            trajectory.write_if_needed(simulation);
+        }
+        if let Some(time_series) = &mut time_series {
+            time_series.write_if_needed(simulation);
+        }
+        checkpointer.save_if_needed(simulation);
+    }
+    checkpointer.save(simulation);
+
+    finalize_npt(simulation, config);
+}
+
+// One production sweep: N_disks NVT steps, fewer for the last one of the
+// run, then an attempt to change the volume. Observables are sampled every
+// 100 sweeps.
+pub fn npt_sweep(
+    simulation: &mut simulation::Simulation,
     pressure_over_kt: f64,
+    nb_equilibration_steps: u32,
     nb_steps: u32,
-) -> thermo::Thermo {
-    let mut thermo = thermo::Thermo::empty_thermo();
+) {
+    let nb_disks = simulation.state.disks.len() as u32;
     let number_of_sweeps_between_thermo_update = 100;
+    let sweep_id = (simulation.step - nb_equilibration_steps) / nb_disks;
+    let nb_steps_to_do = nb_disks.min(nb_equilibration_steps + nb_steps - simulation.step);
+    let nb_success = displacement_moves(
+        &mut simulation.state,
+        nb_steps_to_do,
+        &simulation.displacement,
+        &mut simulation.rng,
+    );
+    simulation.nb_displacement_trials += nb_steps_to_do as u64;
+    simulation.nb_displacement_success += nb_success as u64;
 
-    // We’ll do N_disks NVT steps between attempts to change the volume
-    let number_of_sweeps = (nb_steps as f32 / state.disks.len() as f32).ceil() as u32;
-
-    let mut rng = rand::thread_rng();
-    let mut nb_accepted = 0;
-    let max_volume_change = 2.0 / pressure_over_kt;
-    let mut acceptance_nvt_sum = 0.0;
-    for sweep_id in 0..number_of_sweeps {
-        // Do a number of NVT step equal to the number of disks
-        let nvt_thermo = sample_nvt(state, state.disks.len() as u32);
-        acceptance_nvt_sum += nvt_thermo.nvt_acceptance_rate;
-
-        // Try to change the volume
-        let volume_change: f64 = (rng.gen::<f64>() - 0.5) * 2.0 * max_volume_change;
-        // Select either x or y direction
-        let change_along_x: bool = rng.gen_bool(0.5);
-        // Compute proba now, before checking for overlap. Because if we say no, no need to check for overlaps
-        let volume_before = state.sim_box.lx * state.sim_box.ly;
-        let ratio = 1.0 + volume_change / volume_before;
-        let volume_after = state.sim_box.lx * ratio * state.sim_box.ly;
-
-        let probability = (-(state.disks.len() as f64 * (volume_after.ln() - volume_before.ln())
-            + pressure_over_kt * (volume_after - volume_before)))
-            .exp();
-        let mut accept_volume_change = false;
-        // Probability is good, we’ll check for overlap
-        if probability > rng.gen::<f64>() {
-            if change_along_x {
-                state.sim_box.lx *= ratio;
-                for disk_id in 0..state.disks.len() {
-                    state.update_disk_coordinates(
-                        disk_id,
-                        state.disks[disk_id].position.x * ratio,
-                        state.disks[disk_id].position.y,
-                    );
-                }
-            } else {
-                state.sim_box.ly *= ratio;
-                for disk_id in 0..state.disks.len() {
-                    state.update_disk_coordinates(
-                        disk_id,
-                        state.disks[disk_id].position.x,
-                        state.disks[disk_id].position.y * ratio,
-                    );
-                }
-            }
+    simulation.nb_volume_trials += 1;
+    if volume_move(
+        &mut simulation.state,
+        pressure_over_kt,
+        simulation.volume.max_volume_change,
+        &mut simulation.rng,
+    ) {
+        simulation.nb_volume_success += 1;
+    }
+
+    // Record time series of thermo quantities
+    if sweep_id.is_multiple_of(number_of_sweeps_between_thermo_update) {
+        simulation
+            .thermo
+            .sample_observables(&mut simulation.state, sweep_id * nb_disks);
+    }
+    simulation.step += nb_steps_to_do;
+}
 
-            if state.are_any_disks_overlapping() {
-                // Revert all the changes
-                if change_along_x {
-                    state.sim_box.lx /= ratio;
-                    for disk_id in 0..state.disks.len() {
-                        state.update_disk_coordinates(
-                            disk_id,
-                            state.disks[disk_id].position.x / ratio,
-                            state.disks[disk_id].position.y,
-                        );
-                    }
-                } else {
-                    state.sim_box.ly /= ratio;
-                    for disk_id in 0..state.disks.len() {
-                        state.update_disk_coordinates(
-                            disk_id,
-                            state.disks[disk_id].position.x,
-                            state.disks[disk_id].position.y / ratio,
-                        );
-                    }
+pub fn finalize_npt(simulation: &mut simulation::Simulation, config: &config::Config) {
+    let thermo = &mut simulation.thermo;
+    thermo.max_displacement = simulation.displacement.max_displacement;
+    thermo.max_volume_change = simulation.volume.max_volume_change;
+    thermo.npt_acceptance_rate =
+        simulation.nb_volume_success as f64 / simulation.nb_volume_trials as f64;
+    thermo.nvt_acceptance_rate =
+        simulation.nb_displacement_success as f64 / simulation.nb_displacement_trials as f64;
+    thermo.detect_equilibration(&simulation.state, config.contact_fit);
+    thermo.finalize_observables();
+}
+
+// Tune the step size every 10 sweeps and the volume change every 100 sweeps,
+// both are frozen afterwards. The last sweep is shorter when the
+// equilibration is not a whole number of sweeps.
+pub fn equilibrate_npt(
+    simulation: &mut simulation::Simulation,
+    pressure_over_kt: f64,
+    nb_equilibration_steps: u32,
+    checkpointer: &mut simulation::Checkpointer,
+    time_series: &mut Option<time_series::TimeSeriesWriter>,
+) {
+    let nb_disks = simulation.state.disks.len() as u32;
+    while simulation.step < nb_equilibration_steps {
+        let nb_steps_to_do = nb_disks.min(nb_equilibration_steps - simulation.step);
+        simulation.nb_tuning_displacement_success += displacement_moves(
+            &mut simulation.state,
+            nb_steps_to_do,
+            &simulation.displacement,
+            &mut simulation.rng,
+        ) as u64;
+        if volume_move(
+            &mut simulation.state,
+            pressure_over_kt,
+            simulation.volume.max_volume_change,
+            &mut simulation.rng,
+        ) {
+            simulation.nb_tuning_volume_success += 1;
+        }
+        simulation.step += nb_steps_to_do;
+
+        if simulation.step.is_multiple_of(10 * nb_disks) {
+            simulation.displacement.tune(
+                simulation.nb_tuning_displacement_success as f64 / (10 * nb_disks) as f64,
+                &simulation.state.sim_box,
+            );
+            simulation.nb_tuning_displacement_success = 0;
+        }
+        if simulation.step.is_multiple_of(100 * nb_disks) {
+            simulation.volume.tune(
+                simulation.nb_tuning_volume_success as f64 / 100.0,
+                &simulation.state.sim_box,
+            );
+            simulation.nb_tuning_volume_success = 0;
+        }
+        if let Some(time_series) = time_series {
+            time_series.write_if_needed(simulation);
+        }
+        checkpointer.save_if_needed(simulation);
+    }
+}
+
+// Rescale the box along x or y, returns whether the change was accepted
+fn volume_move<R: Rng>(
+    state: &mut state::State,
+    pressure_over_kt: f64,
+    max_volume_change: f64,
+    rng: &mut R,
+) -> bool {
+    let volume_change: f64 = (rng.gen::<f64>() - 0.5) * 2.0 * max_volume_change;
+    // Select either x or y direction
+    let change_along_x: bool = rng.gen_bool(0.5);
+    // Compute proba now, before checking for overlap. Because if we say no, no need to check for overlaps
+    let volume_before = state.sim_box.lx * state.sim_box.ly;
+    let ratio = 1.0 + volume_change / volume_before;
+    let volume_after = state.sim_box.lx * ratio * state.sim_box.ly;
+
+    // exp(-βP ΔV + N ln(V'/V)), the positions are scaled with the box
+    let probability = (state.disks.len() as f64 * (volume_after.ln() - volume_before.ln())
+        - pressure_over_kt * (volume_after - volume_before))
+        .exp();
+    let mut accept_volume_change = false;
+    // Probability is good, we’ll check for overlap
+    if probability > rng.gen::<f64>() {
+        state.scale_box(change_along_x, ratio);
+        if state.are_any_disks_overlapping() {
+            // Revert the change
+            state.scale_box(change_along_x, 1.0 / ratio);
+        } else {
+            accept_volume_change = true;
+        }
+    }
+    if accept_volume_change {
+        // TODO: do a smarter thing
+        state.update_grid();
+    }
+    accept_volume_change
+}
+
+// Insertions and deletions of disks, all of the radius of the initial ones
+pub struct ExchangeMove {
+    pub activity: f64,
+    pub radius: f64,
+}
+
+impl ExchangeMove {
+    pub fn new(activity: f64, state: &state::State) -> ExchangeMove {
+        if state.disks.is_empty() {
+            panic!("μVT runs need at least one disk to start from");
+        }
+        let radius = state.disks[0].radius;
+        if state.disks.iter().any(|disk| disk.radius != radius) {
+            panic!("μVT runs are only available for monodisperse disks");
+        }
+        ExchangeMove { activity, radius }
+    }
+}
+
+// Accepted moves out of the trials of each kind
+#[derive(Default)]
+struct MuvtCounts {
+    displacement_trials: u64,
+    displacement_success: u64,
+    insertion_trials: u64,
+    insertion_success: u64,
+    deletion_trials: u64,
+    deletion_success: u64,
+}
+
+pub fn sample_muvt(
+    simulation: &mut simulation::Simulation,
+    activity: f64,
+    config: &config::Config,
+) {
+    if config.dynamics.is_some() {
+        panic!("Dynamics are only available at fixed number of disks");
+    }
+    // The test particle average assumes a fixed number of disks
+    if config.widom.is_some() {
+        panic!(
+            "Widom insertions are not available in μVT, the activity sets the chemical potential"
+        );
+    }
+    let nb_equilibration_steps = config.n_equilibration_steps.unwrap_or(0);
+    let nb_steps = config.n_production_steps;
+    // The number of disks changes, the intervals follow the initial one
+    let number_steps_between_updates = 100 * config.n_disk;
+    let number_steps_between_tuning = 10 * config.n_disk;
+    if simulation.step == 0 {
+        simulation
+            .thermo
+            .initialize_observables(config, &simulation.state, &simulation.rng);
+    }
+    let exchange = ExchangeMove::new(activity, &simulation.state);
+    let mut checkpointer = simulation::Checkpointer::from_config(config, simulation.step);
+    let mut trajectory = trajectory::TrajectoryWriter::from_config(config, simulation);
+    let mut time_series = time_series::TimeSeriesWriter::from_config(config, simulation);
+
+    // Tune the step size, it is frozen afterwards
+    while simulation.step < nb_equilibration_steps {
+        let mut nb_steps_to_do = (number_steps_between_tuning
+            - simulation.step % number_steps_between_tuning)
+            .min(nb_equilibration_steps - simulation.step);
+        if let Some(time_series) = &time_series {
+            nb_steps_to_do = nb_steps_to_do.min(time_series.steps_to_next_row(simulation.step));
+        }
+        let counts = muvt_moves(
+            &mut simulation.state,
+            nb_steps_to_do,
+            &simulation.displacement,
+            &exchange,
+            &mut simulation.rng,
+            None,
+        );
+        simulation.nb_tuning_displacement_trials += counts.displacement_trials;
+        simulation.nb_tuning_displacement_success += counts.displacement_success;
+        simulation.step += nb_steps_to_do;
+        let end_of_window = simulation.step.is_multiple_of(number_steps_between_tuning)
+            || simulation.step == nb_equilibration_steps;
+        if end_of_window && simulation.nb_tuning_displacement_trials > 0 {
+            simulation.displacement.tune(
+                simulation.nb_tuning_displacement_success as f64
+                    / simulation.nb_tuning_displacement_trials as f64,
+                &simulation.state.sim_box,
+            );
+            simulation.nb_tuning_displacement_success = 0;
+            simulation.nb_tuning_displacement_trials = 0;
+        }
+        if let Some(time_series) = &mut time_series {
+            time_series.write_if_needed(simulation);
+        }
+        checkpointer.save_if_needed(simulation);
+    }
+
+    while simulation.step < nb_equilibration_steps + nb_steps {
+        let production_step = simulation.step - nb_equilibration_steps;
+        if production_step.is_multiple_of(number_steps_between_updates) {
+            simulation
+                .thermo
+                .sample_observables(&mut simulation.state, production_step);
+        }
+        let mut nb_steps_to_do = (number_steps_between_updates
+            - production_step % number_steps_between_updates)
+            .min(nb_steps - production_step);
+        if let Some(time_series) = &time_series {
+            nb_steps_to_do = nb_steps_to_do.min(time_series.steps_to_next_row(simulation.step));
+        }
+        let counts = muvt_moves(
+            &mut simulation.state,
+            nb_steps_to_do,
+            &simulation.displacement,
+            &exchange,
+            &mut simulation.rng,
+            Some(&mut simulation.thermo.number_of_disks_histogram),
+        );
+        simulation.nb_displacement_trials += counts.displacement_trials;
+        simulation.nb_displacement_success += counts.displacement_success;
+        simulation.nb_insertion_trials += counts.insertion_trials;
+        simulation.nb_insertion_success += counts.insertion_success;
+        simulation.nb_deletion_trials += counts.deletion_trials;
+        simulation.nb_deletion_success += counts.deletion_success;
+        simulation.step += nb_steps_to_do;
+        if let Some(trajectory) = &mut trajectory {
+            trajectory.write_if_needed(simulation);
+        }
+        if let Some(time_series) = &mut time_series {
+            time_series.write_if_needed(simulation);
+        }
+        checkpointer.save_if_needed(simulation);
+    }
+    checkpointer.save(simulation);
+
+    let thermo = &mut simulation.thermo;
+    thermo.max_displacement = simulation.displacement.max_displacement;
+    thermo.nvt_acceptance_rate =
+        simulation.nb_displacement_success as f64 / simulation.nb_displacement_trials as f64;
+    thermo.insertion_acceptance_rate =
+        simulation.nb_insertion_success as f64 / simulation.nb_insertion_trials as f64;
+    thermo.deletion_acceptance_rate =
+        simulation.nb_deletion_success as f64 / simulation.nb_deletion_trials as f64;
+    thermo.detect_equilibration(&simulation.state, config.contact_fit);
+    thermo.finalize_observables();
+}
+
+// Each step is a displacement, an insertion or a deletion, with probabilities
+// 1/2, 1/4 and 1/4. The histogram of the number of disks is updated after
+// every step.
+fn muvt_moves<R: Rng>(
+    state: &mut state::State,
+    nb_steps: u32,
+    displacement: &DisplacementMove,
+    exchange: &ExchangeMove,
+    rng: &mut R,
+    mut histogram: Option<&mut Vec<u64>>,
+) -> MuvtCounts {
+    let mut counts = MuvtCounts::default();
+    let area = state.sim_box.lx * state.sim_box.ly;
+    for _ in 0..nb_steps {
+        let nb_disks = state.disks.len();
+        if rng.gen_bool(0.5) {
+            counts.displacement_trials += 1;
+            if nb_disks > 0 && displacement_moves(state, 1, displacement, rng) == 1 {
+                counts.displacement_success += 1;
+            }
+        } else if rng.gen_bool(0.5) {
+            // Accepted with probability min(1, z A / (N + 1)) without overlap
+            counts.insertion_trials += 1;
+            let position = geometry::Position {
+                x: rng.gen::<f64>() * state.sim_box.lx,
+                y: rng.gen::<f64>() * state.sim_box.ly,
+            };
+            let probability = exchange.activity * area / (nb_disks + 1) as f64;
+            if probability > rng.gen::<f64>()
+                && !state.is_position_overlapping(&position, exchange.radius)
+            {
+                state.add_disk(disks::Disk {
+                    position,
+                    radius: exchange.radius,
+                    species: 0,
+                    cell_id: 0,
+                    displacement: geometry::Position { x: 0.0, y: 0.0 },
+                });
+                counts.insertion_success += 1;
+            }
+        } else {
+            // Accepted with probability min(1, N / z A)
+            counts.deletion_trials += 1;
+            if nb_disks > 0 {
+                let disk_id = rng.gen_range(0..nb_disks);
+                let probability = nb_disks as f64 / (exchange.activity * area);
+                if probability > rng.gen::<f64>() {
+                    state.remove_disk(disk_id);
+                    counts.deletion_success += 1;
                 }
-            } else {
-                accept_volume_change = true;
             }
         }
-        if accept_volume_change {
-            // TODO: do a smarter thing
-            state.update_grid();
-            nb_accepted += 1;
+        if let Some(histogram) = histogram.as_mut() {
+            let nb_disks = state.disks.len();
+            if histogram.len() <= nb_disks {
+                histogram.resize(nb_disks + 1, 0);
+            }
+            histogram[nb_disks] += 1;
+        }
+    }
+    counts
+}
+
+// Initial configuration of the test runs, the square hexagonal packing of
+// their n_disk disks
+#[cfg(test)]
+pub(crate) fn test_state(config: &config::Config) -> state::State {
+    let n_side = (config.n_disk as f64).sqrt().round() as u32;
+    assert_eq!(
+        n_side * n_side,
+        config.n_disk,
+        "Test runs need a square number of disks"
+    );
+    state::State::hexagonal_packing(n_side, n_side, config.packing_fraction)
+}
+
+#[cfg(test)]
+pub(crate) fn test_simulation(config: &config::Config) -> simulation::Simulation {
+    let (rng, seed) = create_rng(config.seed);
+    simulation::Simulation::new(test_state(config), rng, seed, config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_same_seed_same_run() {
+        let config: config::Config = serde_yaml::from_str(
+            "{n_disk: 16, packing_fraction: 0.5, n_production_steps: 5000, seed: 42}",
+        )
+        .unwrap();
+        let mut final_positions: Vec<Vec<(f64, f64)>> = Vec::new();
+        for _ in 0..2 {
+            let mut simulation = test_simulation(&config);
+            assert_eq!(simulation.thermo.seed, 42);
+            sample_nvt(&mut simulation, &config);
+            final_positions.push(positions(&simulation.state));
+        }
+        assert_eq!(final_positions[0], final_positions[1]);
+    }
+
+    #[test]
+    fn test_restart_from_checkpoint() {
+        let config: config::Config = serde_yaml::from_str(
+            "{n_disk: 16, packing_fraction: 0.5, n_production_steps: 5000, seed: 7}",
+        )
+        .unwrap();
+        let mut simulation = test_simulation(&config);
+        sample_nvt(&mut simulation, &config);
+
+        // Same run, stopped half way and resumed from a checkpoint
+        let filepath = std::env::temp_dir().join("hard-disks-test-checkpoint.bin");
+        let first_half: config::Config = serde_yaml::from_str(&format!(
+            "{{n_disk: 16, packing_fraction: 0.5, n_production_steps: 2500, seed: 7, \
+            checkpoint_interval: 1000, checkpoint_file: {}}}",
+            filepath.display()
+        ))
+        .unwrap();
+        let mut first_simulation = test_simulation(&first_half);
+        sample_nvt(&mut first_simulation, &first_half);
+        let mut resumed_simulation = simulation::Simulation::from_checkpoint(&filepath);
+        std::fs::remove_file(&filepath).unwrap();
+        assert_eq!(resumed_simulation.step, 2500);
+        sample_nvt(&mut resumed_simulation, &config);
+
+        assert_eq!(
+            positions(&simulation.state),
+            positions(&resumed_simulation.state)
+        );
+        assert_eq!(
+            simulation.thermo.nvt_acceptance_rate,
+            resumed_simulation.thermo.nvt_acceptance_rate
+        );
+    }
+
+    #[test]
+    fn test_npt_restart_from_checkpoint() {
+        let config: config::Config = serde_yaml::from_str(
+            "{n_disk: 16, packing_fraction: 0.5, pressure: 4.0, seed: 7, \
+            n_equilibration_steps: 3200, n_production_steps: 3200}",
+        )
+        .unwrap();
+        let mut simulation = test_simulation(&config);
+        sample_npt(&mut simulation, 4.0, &config);
+
+        // Stopped in the middle of the tuning windows of 10 and 100 sweeps
+        let filepath = std::env::temp_dir().join("hard-disks-test-checkpoint-npt.bin");
+        let first_part: config::Config = serde_yaml::from_str(&format!(
+            "{{n_disk: 16, packing_fraction: 0.5, pressure: 4.0, seed: 7, \
+            n_equilibration_steps: 880, n_production_steps: 0, \
+            checkpoint_interval: 1000, checkpoint_file: {}}}",
+            filepath.display()
+        ))
+        .unwrap();
+        let mut first_simulation = test_simulation(&first_part);
+        sample_npt(&mut first_simulation, 4.0, &first_part);
+        let mut resumed_simulation = simulation::Simulation::from_checkpoint(&filepath);
+        std::fs::remove_file(&filepath).unwrap();
+        assert_eq!(resumed_simulation.step, 880);
+        sample_npt(&mut resumed_simulation, 4.0, &config);
+
+        assert_eq!(
+            positions(&simulation.state),
+            positions(&resumed_simulation.state)
+        );
+        assert_eq!(
+            simulation.volume.max_volume_change,
+            resumed_simulation.volume.max_volume_change
+        );
+        assert_eq!(
+            simulation.thermo.npt_acceptance_rate,
+            resumed_simulation.thermo.npt_acceptance_rate
+        );
+    }
+
+    #[test]
+    fn test_nvt_pressure_blocking() {
+        let config: config::Config = serde_yaml::from_str(
+            "{n_disk: 16, packing_fraction: 0.5, n_production_steps: 160000, seed: 13}",
+        )
+        .unwrap();
+        let mut simulation = test_simulation(&config);
+        sample_nvt(&mut simulation, &config);
+
+        // One contact pressure per g(r) block left after the equilibration
+        let analysis = &simulation.thermo.statistics["pressure"];
+        let pressure = simulation.thermo.pressure.unwrap();
+        assert_eq!(
+            analysis.number_of_samples,
+            simulation.thermo.g_of_r.number_of_blocks()
+        );
+        assert!(analysis.number_of_samples >= 2);
+        assert!(analysis.error > 0.0 && analysis.error.is_finite());
+        assert_eq!(pressure.value, analysis.value);
+        assert_eq!(pressure.error, analysis.error);
+    }
+
+    #[test]
+    fn test_volume_acceptance_converges() {
+        // The initial amplitude is far too large for both targets
+        for target in [0.2, 0.5] {
+            let config: config::Config = serde_yaml::from_str(&format!(
+                "{{n_disk: 16, packing_fraction: 0.5, pressure: 4.0, seed: 9, \
+                max_volume_change: 20.0, target_volume_acceptance: {}, \
+                n_equilibration_steps: 48000, n_production_steps: 80000}}",
+                target
+            ))
+            .unwrap();
+            let mut simulation = test_simulation(&config);
+            sample_npt(&mut simulation, 4.0, &config);
+
+            assert_eq!(simulation.step, 48000 + 80000);
+            assert_eq!(simulation.nb_volume_trials, 5000);
+            let acceptance = simulation.thermo.npt_acceptance_rate;
+            assert!(
+                (acceptance - target).abs() < 0.1,
+                "acceptance {} for a target of {}",
+                acceptance,
+                target
+            );
+        }
+    }
+
+    #[test]
+    fn test_npt_ideal_gas_limit() {
+        // V^N exp(-βPV) gives <V> = (N + 1) / βP, the excluded area adds
+        // 2φ = 0.4%. Volume moves rescale one side at a time, with many disks
+        // they are small enough that the box keeps its shape over the run.
+        let packing_fraction = 0.002;
+        let pressure = packing_fraction / (PI * 0.25);
+        let config: config::Config = serde_yaml::from_str(&format!(
+            "{{n_disk: 256, packing_fraction: {}, pressure: {}, seed: 3, \
+            max_volume_change: 5000.0, n_equilibration_steps: 128000, \
+            n_production_steps: 768000}}",
+            packing_fraction, pressure
+        ))
+        .unwrap();
+        let mut simulation = test_simulation(&config);
+        // Fewer, larger cells for a faster rebuild after each volume change
+        simulation.state.set_min_cell_size(10.0);
+        let mut checkpointer = simulation::Checkpointer::disabled();
+        equilibrate_npt(
+            &mut simulation,
+            pressure,
+            128000,
+            &mut checkpointer,
+            &mut None,
+        );
+
+        let mut volume_sum = 0.0;
+        let mut nb_sweeps = 0;
+        while simulation.step < 128000 + 768000 {
+            npt_sweep(&mut simulation, pressure, 128000, 768000);
+            volume_sum += simulation.state.sim_box.lx * simulation.state.sim_box.ly;
+            nb_sweeps += 1;
         }
+        let mean_volume = volume_sum / nb_sweeps as f64;
+        let expected = 257.0 / pressure;
+        assert!(
+            (mean_volume / expected - 1.0).abs() < 0.02,
+            "<V> = {} instead of {}",
+            mean_volume,
+            expected
+        );
+    }
+
+    #[test]
+    fn test_widom_virial_limit() {
+        // βμ_ex = 2 B2 ρ + 3/2 B3 ρ² with B2 ρ = 2φ and B3 / B2² = 4/3 - √3/π
+        // for hard disks, and βP = ρ (1 + B2 ρ + B3 ρ²)
+        let packing_fraction: f64 = 0.05;
+        let b3_over_b2_sq = 4.0 / 3.0 - 3f64.sqrt() / PI;
+        let b2_rho = 2.0 * packing_fraction;
+        let expected = 2.0 * b2_rho + 1.5 * b3_over_b2_sq * b2_rho * b2_rho;
+        let number_density = packing_fraction / (PI * 0.25);
+        let pressure = number_density * (1.0 + b2_rho + b3_over_b2_sq * b2_rho * b2_rho);
+        // NVT, then NPT at the pressure of the same density
+        for pressure in [None, Some(pressure)] {
+            let ensemble = pressure.map_or(String::new(), |p| format!(", pressure: {}", p));
+            let config: config::Config = serde_yaml::from_str(&format!(
+                "{{n_disk: 64, packing_fraction: {}, seed: 17, widom: {{insertions: 1000}}, \
+                n_equilibration_steps: 64000, n_production_steps: 1280000{}}}",
+                packing_fraction, ensemble
+            ))
+            .unwrap();
+            let mut simulation = test_simulation(&config);
+            match pressure {
+                Some(pressure) => sample_npt(&mut simulation, pressure, &config),
+                None => sample_nvt(&mut simulation, &config),
+            }
 
-        // Record time series of thermo quantities
-        if sweep_id % number_of_sweeps_between_thermo_update == 0 {
-            thermo.step.push(sweep_id * state.disks.len() as u32);
-            thermo.density.push(state.get_density());
+            let excess_chemical_potential = simulation.thermo.excess_chemical_potential.unwrap();
+            // The second order, 0.012, is well above the error bars
+            assert!(
+                (excess_chemical_potential.value - expected).abs()
+                    < (4.0 * excess_chemical_potential.error).max(0.004)
+            );
         }
     }
-    thermo.npt_acceptance_rate = nb_accepted as f64 / number_of_sweeps as f64;
-    thermo.nvt_acceptance_rate = acceptance_nvt_sum as f64 / number_of_sweeps as f64;
-    return thermo;
+
+    fn positions(state: &state::State) -> Vec<(f64, f64)> {
+        state
+            .disks
+            .iter()
+            .map(|disk| (disk.position.x, disk.position.y))
+            .collect()
+    }
+
+    #[test]
+    fn test_muvt_dilute_limit() {
+        // Few overlaps at φ ~ 0.01, <N> is close to z A
+        let config: config::Config = serde_yaml::from_str(
+            "{n_disk: 16, packing_fraction: 0.01, n_production_steps: 200000, \
+            n_equilibration_steps: 10000, activity: 0.01, seed: 11}",
+        )
+        .unwrap();
+        let mut simulation = test_simulation(&config);
+        let area = simulation.state.sim_box.lx * simulation.state.sim_box.ly;
+        sample_muvt(&mut simulation, 0.01, &config);
+
+        let histogram = &simulation.thermo.number_of_disks_histogram;
+        assert_eq!(histogram.iter().sum::<u64>(), 200000);
+        let mean_number = histogram
+            .iter()
+            .enumerate()
+            .map(|(n, count)| n as f64 * *count as f64)
+            .sum::<f64>()
+            / 200000.0;
+        assert!((mean_number / (0.01 * area) - 1.0).abs() < 0.15);
+        assert!(!simulation.state.are_any_disks_overlapping());
+    }
 }
@@ -0,0 +1,162 @@
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use crate::config;
+use crate::sample;
+use crate::state;
+use crate::thermo;
+
+// Everything needed to carry on a run where it stopped
+#[derive(Serialize, Deserialize)]
+pub struct Simulation {
+    pub state: state::State,
+    pub rng: sample::SimulationRng,
+    // Steps done so far, equilibration included (chains for event-chain runs)
+    pub step: u32,
+    pub displacement: sample::DisplacementMove,
+    pub volume: sample::VolumeMove,
+    pub nb_displacement_trials: u64,
+    pub nb_displacement_success: u64,
+    pub nb_volume_trials: u64,
+    pub nb_volume_success: u64,
+    pub nb_insertion_trials: u64,
+    pub nb_insertion_success: u64,
+    pub nb_deletion_trials: u64,
+    pub nb_deletion_success: u64,
+    // Moves in the current tuning windows of the equilibration, which don’t
+    // line up with the checkpoints or the time series rows
+    pub nb_tuning_displacement_trials: u64,
+    pub nb_tuning_displacement_success: u64,
+    pub nb_tuning_volume_success: u64,
+    // Lifted-excess pressure of event-chain runs, by blocks of chains
+    pub pressure_blocks: thermo::BlockAverage,
+    pub thermo: thermo::Thermo,
+    // Output files of the run, None until they are first written
+    pub trajectory_output: Option<OutputPosition>,
+    pub time_series_output: Option<OutputPosition>,
+}
+
+// How far an output file was written when the checkpoint was taken. A
+// restarted run cuts the file back there before appending, so frames written
+// after the checkpoint don’t show up twice.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default)]
+pub struct OutputPosition {
+    // Length of the file in bytes
+    pub bytes: u64,
+    // Frames, or rows, in the file
+    pub frames: u64,
+    // Step of the last one
+    pub last_step: Option<u32>,
+}
+
+impl OutputPosition {
+    pub fn truncate_text_file(&self, filepath: &Path) {
+        let file = OpenOptions::new()
+            .write(true)
+            .open(filepath)
+            .expect("Could not open output file.");
+        file.set_len(self.bytes).unwrap();
+    }
+}
+
+impl Simulation {
+    pub fn new(
+        state: state::State,
+        rng: sample::SimulationRng,
+        seed: u64,
+        config: &config::Config,
+    ) -> Simulation {
+        let mut thermo = thermo::Thermo::empty_thermo();
+        thermo.seed = seed;
+        Simulation {
+            state,
+            rng,
+            step: 0,
+            displacement: sample::DisplacementMove::from_config(config),
+            volume: sample::VolumeMove::from_config(config),
+            nb_displacement_trials: 0,
+            nb_displacement_success: 0,
+            nb_volume_trials: 0,
+            nb_volume_success: 0,
+            nb_insertion_trials: 0,
+            nb_insertion_success: 0,
+            nb_deletion_trials: 0,
+            nb_deletion_success: 0,
+            nb_tuning_displacement_trials: 0,
+            nb_tuning_displacement_success: 0,
+            nb_tuning_volume_success: 0,
+            pressure_blocks: thermo::BlockAverage::new(1),
+            thermo,
+            trajectory_output: None,
+            time_series_output: None,
+        }
+    }
+
+    pub fn from_checkpoint(filepath: &Path) -> Simulation {
+        let file = File::open(filepath).expect("Could not open checkpoint file.");
+        bincode::deserialize_from(BufReader::new(file)).expect("Could not read checkpoint.")
+    }
+
+    pub fn write_checkpoint(&self, filepath: &Path) {
+        // Write to a temporary file first, so a job killed while writing
+        // doesn’t leave a broken checkpoint behind
+        let tmp_filepath = filepath.with_extension("tmp");
+        let file = File::create(&tmp_filepath).expect("Could not create checkpoint file.");
+        let mut writer = BufWriter::new(file);
+        bincode::serialize_into(&mut writer, &self).expect("Could not write checkpoint.");
+        // Dropping the writer would flush it and ignore the errors, and the
+        // data must be on disk before the rename replaces the previous one
+        writer.flush().expect("Could not write checkpoint.");
+        writer
+            .get_ref()
+            .sync_all()
+            .expect("Could not write checkpoint.");
+        std::fs::rename(&tmp_filepath, filepath).expect("Could not replace checkpoint file.");
+    }
+}
+
+pub struct Checkpointer {
+    filepath: PathBuf,
+    interval: Option<u32>,
+    last_step: u32,
+}
+
+impl Checkpointer {
+    pub fn from_config(config: &config::Config, step: u32) -> Checkpointer {
+        let filepath = config
+            .checkpoint_file
+            .clone()
+            .unwrap_or("checkpoint.bin".to_string());
+        Checkpointer {
+            filepath: PathBuf::from(filepath),
+            interval: config.checkpoint_interval,
+            last_step: step,
+        }
+    }
+
+    // Never writes, e.g. for the replicas of a replica-exchange run
+    pub fn disabled() -> Checkpointer {
+        Checkpointer {
+            filepath: PathBuf::new(),
+            interval: None,
+            last_step: 0,
+        }
+    }
+
+    pub fn save_if_needed(&mut self, simulation: &Simulation) {
+        if let Some(interval) = self.interval {
+            if simulation.step - self.last_step >= interval {
+                self.save(simulation);
+            }
+        }
+    }
+
+    pub fn save(&mut self, simulation: &Simulation) {
+        if self.interval.is_some() {
+            simulation.write_checkpoint(&self.filepath);
+            self.last_step = simulation.step;
+        }
+    }
+}
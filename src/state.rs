@@ -1,11 +1,13 @@
 use std::f64::consts::PI;
-use std::fs::File;
+use std::fs::{read_to_string, File};
 use std::io::Write;
 use std::path::Path;
 
 use crate::disks;
 use crate::geometry;
+use serde::{Deserialize, Serialize};
 
+#[derive(Serialize, Deserialize, Clone)]
 pub struct State {
     pub disks: Vec<disks::Disk>,
     pub sim_box: geometry::Box,
@@ -13,6 +15,7 @@ pub struct State {
 }
 
 impl State {
+    #[allow(clippy::needless_return)]
     pub fn get_neighbor_disks(&self, disk_id: usize) -> Vec<usize> {
         let mut neighbor_disks: Vec<usize> = Vec::new();
         let current_cell_id = self.disks[disk_id].cell_id;
@@ -31,6 +34,7 @@ impl State {
         return neighbor_disks;
     }
 
+    #[allow(clippy::needless_return)]
     pub fn is_disk_overlapping(&self, disk_id: usize) -> bool {
         for neighbor_id in self.get_neighbor_disks(disk_id) {
             if disks::are_disks_overlapping(
@@ -44,6 +48,7 @@ impl State {
         return false;
     }
 
+    #[allow(clippy::needless_return)]
     pub fn are_any_disks_overlapping(&self) -> bool {
         for disk_id in 0..self.disks.len() {
             if self.is_disk_overlapping(disk_id) {
@@ -53,16 +58,73 @@ impl State {
         return false;
     }
 
+    // Distance disk_id can travel along +x (or +y) before hitting another disk,
+    // capped at max_distance. Returns the id of the disk that is hit, if any.
+    pub fn next_collision(
+        &self,
+        disk_id: usize,
+        along_x: bool,
+        max_distance: f64,
+    ) -> (f64, Option<usize>) {
+        // Only the neighboring cells are searched, so a single displacement
+        // can’t be longer than what the cells guarantee to cover
+        let cell_size = if along_x {
+            self.sim_box.lx / self.grid.nx as f64
+        } else {
+            self.sim_box.ly / self.grid.ny as f64
+        };
+        let safe_distance = cell_size - self.disks[disk_id].radius - self.grid.max_radius;
+        // create_grid makes cells wider than a diameter, without that margin
+        // the chain could get stuck without moving or hitting anything
+        if safe_distance <= 0.0 {
+            panic!(
+                "Cells of size {} are too small for event chains, they must be wider than {}",
+                cell_size,
+                self.disks[disk_id].radius + self.grid.max_radius
+            );
+        }
+        let mut distance = max_distance.min(safe_distance);
+        let mut target: Option<usize> = None;
+
+        for neighbor_id in self.get_neighbor_disks(disk_id) {
+            let mut dx = self.disks[neighbor_id].position.x - self.disks[disk_id].position.x;
+            let mut dy = self.disks[neighbor_id].position.y - self.disks[disk_id].position.y;
+            geometry::apply_boundary_conditions(&mut dx, &mut dy, &self.sim_box);
+            let (parallel, perpendicular) = if along_x { (dx, dy) } else { (dy, dx) };
+            if parallel <= 0.0 {
+                continue;
+            }
+            let sigma = self.disks[disk_id].radius + self.disks[neighbor_id].radius;
+            if perpendicular.abs() >= sigma {
+                continue;
+            }
+            let gap = (parallel - (sigma * sigma - perpendicular * perpendicular).sqrt()).max(0.0);
+            if gap < distance {
+                distance = gap;
+                target = Some(neighbor_id);
+            }
+        }
+        (distance, target)
+    }
+
     pub fn update_disk_coordinates(&mut self, disk_id: usize, new_x: f64, new_y: f64) {
+        // Moves are always shorter than half the box
+        let mut dx = new_x - self.disks[disk_id].position.x;
+        let mut dy = new_y - self.disks[disk_id].position.y;
+        geometry::apply_boundary_conditions(&mut dx, &mut dy, &self.sim_box);
+        self.disks[disk_id].displacement.x += dx;
+        self.disks[disk_id].displacement.y += dy;
+        self.set_position(disk_id, new_x, new_y);
+    }
+
+    // Move the disk and its cell, without counting it as a displacement
+    fn set_position(&mut self, disk_id: usize, new_x: f64, new_y: f64) {
         self.disks[disk_id].position.x = geometry::put_in_box_x(new_x, &self.sim_box);
         self.disks[disk_id].position.y = geometry::put_in_box_y(new_y, &self.sim_box);
 
-        // Compute the cell id
-        let ix = (self.disks[disk_id].position.x * (self.grid.nx as f64) / self.sim_box.lx).floor()
-            as u32;
-        let iy = (self.disks[disk_id].position.y * (self.grid.ny as f64) / self.sim_box.ly).floor()
-            as u32;
-        let new_cell_id = (ix + self.grid.nx * iy) as usize;
+        let new_cell_id = self
+            .grid
+            .cell_id_from_position(&self.disks[disk_id].position, &self.sim_box);
         if new_cell_id != self.disks[disk_id].cell_id {
             let old_cell_id = self.disks[disk_id].cell_id;
             // Remove the disk id from the old cell
@@ -76,10 +138,125 @@ impl State {
         }
     }
 
+    // Whether a disk of this radius would overlap one of the disks, e.g. at
+    // the trial position of an insertion
+    pub fn is_position_overlapping(&self, position: &geometry::Position, radius: f64) -> bool {
+        if radius + self.grid.max_radius > self.get_cell_size() {
+            panic!(
+                "Disks of radius {} are too large for the cells, the overlaps can’t be checked",
+                radius
+            );
+        }
+        let cell_id = self.grid.cell_id_from_position(position, &self.sim_box);
+        let cell = &self.grid.cells[cell_id];
+        for id in cell.neighbor_ids.iter().chain([cell_id].iter()) {
+            for disk_id in self.grid.cells[*id].disk_ids.iter() {
+                let disk = &self.disks[*disk_id];
+                let sigma = radius + disk.radius;
+                if geometry::distance_sq_periodic(position, &disk.position, &self.sim_box)
+                    < sigma * sigma
+                {
+                    return true;
+                }
+            }
+        }
+        false
+    }
+
+    // Returns the id of the new disk, the last one
+    pub fn add_disk(&mut self, mut disk: disks::Disk) -> usize {
+        disk.position.x = geometry::put_in_box_x(disk.position.x, &self.sim_box);
+        disk.position.y = geometry::put_in_box_y(disk.position.y, &self.sim_box);
+        let disk_id = self.disks.len();
+        if disk.radius > self.grid.max_radius {
+            // The cells are too small for it
+            self.disks.push(disk);
+            self.update_grid();
+            return disk_id;
+        }
+        disk.cell_id = self
+            .grid
+            .cell_id_from_position(&disk.position, &self.sim_box);
+        self.grid.cells[disk.cell_id].disk_ids.push(disk_id);
+        self.disks.push(disk);
+        disk_id
+    }
+
+    // The last disk takes the id of the removed one
+    pub fn remove_disk(&mut self, disk_id: usize) -> disks::Disk {
+        let cell_id = self.disks[disk_id].cell_id;
+        self.grid.cells[cell_id]
+            .disk_ids
+            .retain(|value| *value != disk_id);
+        let disk = self.disks.swap_remove(disk_id);
+        let last_id = self.disks.len();
+        if disk_id < last_id {
+            let moved_cell_id = self.disks[disk_id].cell_id;
+            for id in self.grid.cells[moved_cell_id].disk_ids.iter_mut() {
+                if *id == last_id {
+                    *id = disk_id;
+                }
+            }
+        }
+        disk
+    }
+
+    // Affine rescaling along x or y, e.g. for a volume move. The disks follow
+    // the box without being displaced, their unwrapped displacements are kept.
+    // The cells keep their number, call update_grid once the change is kept.
+    pub fn scale_box(&mut self, along_x: bool, ratio: f64) {
+        if along_x {
+            self.sim_box.lx *= ratio;
+        } else {
+            self.sim_box.ly *= ratio;
+        }
+        for disk_id in 0..self.disks.len() {
+            let position = &self.disks[disk_id].position;
+            if along_x {
+                self.set_position(disk_id, position.x * ratio, position.y);
+            } else {
+                self.set_position(disk_id, position.x, position.y * ratio);
+            }
+        }
+    }
+
     pub fn update_grid(&mut self) {
-        self.grid = geometry::create_grid(&mut self.disks, &self.sim_box);
+        self.grid = geometry::create_grid(&mut self.disks, &self.sim_box, self.grid.min_cell_size);
     }
 
+    pub fn set_min_cell_size(&mut self, min_cell_size: f64) {
+        self.grid = geometry::create_grid(&mut self.disks, &self.sim_box, min_cell_size);
+    }
+
+    pub fn get_cell_size(&self) -> f64 {
+        (self.sim_box.lx / self.grid.nx as f64).min(self.sim_box.ly / self.grid.ny as f64)
+    }
+
+    // Disks whose center is closer than cutoff, which can’t be larger than the cells
+    pub fn get_neighbors_within(&self, disk_id: usize, cutoff: f64) -> Vec<usize> {
+        let cell_size = self.get_cell_size();
+        if cutoff > cell_size {
+            panic!(
+                "Cutoff {} larger than the cells ({}), call set_min_cell_size first",
+                cutoff, cell_size
+            );
+        }
+        let cutoff_sq = cutoff * cutoff;
+        let mut neighbors: Vec<usize> = Vec::new();
+        for neighbor_id in self.get_neighbor_disks(disk_id) {
+            let r_sq = geometry::distance_sq_periodic(
+                &self.disks[disk_id].position,
+                &self.disks[neighbor_id].position,
+                &self.sim_box,
+            );
+            if r_sq < cutoff_sq {
+                neighbors.push(neighbor_id);
+            }
+        }
+        neighbors
+    }
+
+    #[allow(clippy::needless_return, clippy::redundant_field_names)]
     pub fn create_simple_state(n_disks: u32) -> State {
         let radius = 0.5;
         let mut disks: Vec<disks::Disk> = Vec::new();
@@ -94,7 +271,9 @@ impl State {
                 let new_disk = disks::Disk {
                     position: pos,
                     radius: radius,
+                    species: 0,
                     cell_id: 0,
+                    displacement: geometry::Position { x: 0.0, y: 0.0 },
                 };
                 disks.push(new_disk);
                 counter += 1;
@@ -107,7 +286,7 @@ impl State {
         let sim_box = geometry::Box { lx: 20.0, ly: 20.0 };
 
         // create grid list
-        let grid = geometry::create_grid(&mut disks, &sim_box);
+        let grid = geometry::create_grid(&mut disks, &sim_box, 0.0);
 
         return State {
             disks: disks,
@@ -117,6 +296,31 @@ impl State {
     }
 
     pub fn hexagonal_packing(n_row: u32, n_column: u32, packing_fraction: f64) -> State {
+        let number_of_disks = (n_row * n_column) as usize;
+        State::hexagonal_packing_with_sizes(
+            n_row,
+            n_column,
+            packing_fraction,
+            &vec![0.5; number_of_disks],
+            &vec![0; number_of_disks],
+        )
+    }
+
+    // Lattice sites are filled with disks of the given radii and species,
+    // in that order
+    #[allow(
+        clippy::needless_return,
+        clippy::redundant_field_names,
+        clippy::manual_range_contains,
+        clippy::manual_is_multiple_of
+    )]
+    pub fn hexagonal_packing_with_sizes(
+        n_row: u32,
+        n_column: u32,
+        packing_fraction: f64,
+        radii: &[f64],
+        species: &[u32],
+    ) -> State {
         if packing_fraction < 0.0 || packing_fraction > 0.9 {
             panic!("Invalid packing fraction");
         }
@@ -126,10 +330,12 @@ impl State {
         if n_column % 2 != 0 {
             panic!("Number of columns should be even");
         }
-        let number_of_disks = n_row * n_column;
+        let number_of_disks = (n_row * n_column) as usize;
+        if radii.len() != number_of_disks || species.len() != number_of_disks {
+            panic!("One radius and one species are needed per disk");
+        }
 
-        let radius = 0.5;
-        let disk_volume = number_of_disks as f64 * radius * radius * PI;
+        let disk_volume: f64 = radii.iter().map(|radius| radius * radius * PI).sum();
         let box_volume = disk_volume / packing_fraction;
 
         let ly_over_lx = 3.0_f64.sqrt() / 2.0 * (n_row as f64) / (n_column as f64);
@@ -139,6 +345,10 @@ impl State {
 
         let dx = lx / (n_column as f64);
         let dy = ly / (n_row as f64) * 2.0;
+        let max_radius = radii.iter().fold(0.0, |a: f64, b| a.max(*b));
+        if dx < 2.0 * max_radius {
+            panic!("Packing fraction too high to put the largest disks on a lattice");
+        }
 
         let mut disks: Vec<disks::Disk> = Vec::new();
         let mut current_y = dy / 4.0;
@@ -154,8 +364,10 @@ impl State {
                 };
                 let new_disk = disks::Disk {
                     position: pos,
-                    radius: radius,
+                    radius: radii[disks.len()],
+                    species: species[disks.len()],
                     cell_id: 0,
+                    displacement: geometry::Position { x: 0.0, y: 0.0 },
                 };
                 disks.push(new_disk);
                 current_x += dx;
@@ -165,7 +377,7 @@ impl State {
         let sim_box = geometry::Box { lx: lx, ly: ly };
 
         // create grid list
-        let grid = geometry::create_grid(&mut disks, &sim_box);
+        let grid = geometry::create_grid(&mut disks, &sim_box, 0.0);
 
         return State {
             disks: disks,
@@ -174,25 +386,158 @@ impl State {
         };
     }
 
+    #[allow(clippy::needless_return)]
     pub fn get_density(&self) -> f64 {
-        let radius = self.disks[0].radius;
-        let disk_volume = self.disks.len() as f64 * radius * radius * PI;
+        let disk_volume: f64 = self
+            .disks
+            .iter()
+            .map(|disk| disk.radius * disk.radius * PI)
+            .sum();
         let box_volume = self.sim_box.lx * self.sim_box.ly;
         return disk_volume / box_volume;
     }
 
+    #[allow(clippy::needless_return)]
     pub fn get_number_density(&self) -> f64 {
         let box_volume = self.sim_box.lx * self.sim_box.ly;
         return self.disks.len() as f64 / box_volume;
     }
 
+    pub fn get_mean_radius(&self) -> f64 {
+        let radius_sum: f64 = self.disks.iter().map(|disk| disk.radius).sum();
+        radius_sum / self.disks.len() as f64
+    }
+
+    // Species present in the system, sorted
+    pub fn get_species(&self) -> Vec<u32> {
+        let mut species: Vec<u32> = self.disks.iter().map(|disk| disk.species).collect();
+        species.sort();
+        species.dedup();
+        species
+    }
+
+    pub fn count_species(&self, species: u32) -> usize {
+        self.disks
+            .iter()
+            .filter(|disk| disk.species == species)
+            .count()
+    }
+
+    pub fn get_mean_radius_of_species(&self, species: u32) -> f64 {
+        let radius_sum: f64 = self
+            .disks
+            .iter()
+            .filter(|disk| disk.species == species)
+            .map(|disk| disk.radius)
+            .sum();
+        radius_sum / self.count_species(species) as f64
+    }
+
+    // Read a file written by write_coords_to_file. Lines with only x and y
+    // (older files) use the radius from the header.
+    pub fn from_coords_file(filepath: &Path) -> State {
+        let content = read_to_string(filepath).unwrap_or_else(|error| {
+            panic!(
+                "Could not open coordinates file {}: {}",
+                filepath.display(),
+                error
+            )
+        });
+        let mut lines = content.lines();
+        // Line numbers start at 1 in the messages
+        let parse = |value: &str, line_number: usize| -> f64 {
+            value.parse().unwrap_or_else(|_| {
+                panic!(
+                    "Could not read {:?} at line {} of {}",
+                    value,
+                    line_number,
+                    filepath.display()
+                )
+            })
+        };
+
+        let header: Vec<f64> = lines
+            .next()
+            .unwrap_or_else(|| panic!("Empty coordinates file {}", filepath.display()))
+            .split_whitespace()
+            .map(|value| parse(value, 1))
+            .collect();
+        if header.len() != 4 {
+            panic!(
+                "Header of {} should be: number_of_disks mean_radius lx ly",
+                filepath.display()
+            );
+        }
+        let number_of_disks = header[0] as usize;
+        let radius = header[1];
+        let sim_box = geometry::Box {
+            lx: header[2],
+            ly: header[3],
+        };
+
+        let mut disks: Vec<disks::Disk> = Vec::new();
+        for (line_index, line) in lines.enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let line_number = line_index + 2;
+            let coords: Vec<f64> = line
+                .split_whitespace()
+                .map(|value| parse(value, line_number))
+                .collect();
+            if coords.len() < 2 {
+                panic!(
+                    "Line {} of {} should be: x y [radius [species]]",
+                    line_number,
+                    filepath.display()
+                );
+            }
+            let pos = geometry::Position {
+                x: geometry::put_in_box_x(coords[0], &sim_box),
+                y: geometry::put_in_box_y(coords[1], &sim_box),
+            };
+            disks.push(disks::Disk {
+                position: pos,
+                radius: if coords.len() > 2 { coords[2] } else { radius },
+                species: if coords.len() > 3 {
+                    coords[3] as u32
+                } else {
+                    0
+                },
+                cell_id: 0,
+                displacement: geometry::Position { x: 0.0, y: 0.0 },
+            });
+        }
+        if disks.len() != number_of_disks {
+            panic!(
+                "Expected {} disks, found {} in {}",
+                number_of_disks,
+                disks.len(),
+                filepath.display()
+            );
+        }
+
+        State::from_disks(disks, sim_box)
+    }
+
+    // Disks must already be inside the box
+    pub fn from_disks(mut disks: Vec<disks::Disk>, sim_box: geometry::Box) -> State {
+        let grid = geometry::create_grid(&mut disks, &sim_box, 0.0);
+        State {
+            disks,
+            grid,
+            sim_box,
+        }
+    }
+
+    #[allow(clippy::needless_borrows_for_generic_args)]
     pub fn write_coords_to_file(&self, filepath: &Path) {
         let mut file = File::create(&filepath).unwrap();
         writeln!(
             file,
             "{} {} {} {}",
             self.disks.len(),
-            self.disks[0].radius,
+            self.get_mean_radius(),
             self.sim_box.lx,
             self.sim_box.ly
         )
@@ -200,11 +545,132 @@ impl State {
         for disk in self.disks.iter() {
             writeln!(
                 file,
-                "{} {}",
+                "{} {} {} {}",
                 geometry::put_in_box_x(disk.position.x, &self.sim_box),
                 geometry::put_in_box_y(disk.position.y, &self.sim_box),
+                disk.radius,
+                disk.species,
             )
             .unwrap();
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_next_collision() {
+        let state = State::hexagonal_packing(4, 4, 0.85);
+        let lattice_spacing = state.sim_box.lx / 4.0;
+
+        // Disk 1 sits right of disk 0, on the same row
+        let (distance, target) = state.next_collision(0, true, 1.0);
+        assert_eq!(target, Some(1));
+        assert!((distance - (lattice_spacing - 1.0)).abs() < 1e-12);
+
+        // Nothing is hit before the requested distance
+        let (distance, target) = state.next_collision(0, true, 0.01);
+        assert_eq!(target, None);
+        assert_eq!(distance, 0.01);
+    }
+
+    #[test]
+    #[should_panic(expected = "too small for event chains")]
+    fn test_next_collision_cells_one_diameter_wide() {
+        let mut state = State::hexagonal_packing(4, 4, 0.3);
+        // Cells of exactly σ, which create_grid never builds on its own
+        let n_cells = state.sim_box.lx.floor();
+        for disk in state.disks.iter_mut() {
+            disk.radius = state.sim_box.lx / n_cells / 2.2;
+        }
+        state.update_grid();
+        for disk in state.disks.iter_mut() {
+            disk.radius = state.sim_box.lx / n_cells / 2.0;
+        }
+        state.grid.max_radius = state.disks[0].radius;
+        state.next_collision(0, true, 1.0);
+    }
+
+    #[test]
+    fn test_coords_file_round_trip() {
+        let state = State::hexagonal_packing(4, 6, 0.6);
+        let filepath = std::env::temp_dir().join("hard-disks-test-coords.txt");
+        state.write_coords_to_file(&filepath);
+        let read_state = State::from_coords_file(&filepath);
+        std::fs::remove_file(&filepath).unwrap();
+
+        assert_eq!(read_state.disks.len(), 24);
+        assert_eq!(read_state.sim_box.lx, state.sim_box.lx);
+        assert_eq!(read_state.sim_box.ly, state.sim_box.ly);
+        for i in 0..state.disks.len() {
+            assert_eq!(read_state.disks[i].position.x, state.disks[i].position.x);
+            assert_eq!(read_state.disks[i].position.y, state.disks[i].position.y);
+            assert_eq!(read_state.disks[i].radius, state.disks[i].radius);
+        }
+    }
+
+    #[test]
+    fn test_add_and_remove_disks() {
+        let mut state = State::hexagonal_packing(4, 4, 0.15);
+        let position = geometry::Position {
+            x: state.disks[0].position.x + 0.5 * state.sim_box.lx / 4.0,
+            y: state.disks[0].position.y,
+        };
+        assert!(!state.is_position_overlapping(&position, 0.5));
+        assert!(state.is_position_overlapping(&state.disks[5].position.clone(), 0.5));
+
+        let position_x = position.x;
+        let disk_id = state.add_disk(disks::Disk {
+            position,
+            radius: 0.5,
+            species: 0,
+            cell_id: 0,
+            displacement: geometry::Position { x: 0.0, y: 0.0 },
+        });
+        assert_eq!(disk_id, 16);
+        assert!(!state.are_any_disks_overlapping());
+        // The last disk takes the id of the removed one
+        state.remove_disk(0);
+        assert_eq!(state.disks[0].position.x, position_x);
+        state.remove_disk(7);
+        assert_eq!(state.disks.len(), 15);
+
+        // Each disk is in its cell, and only there
+        let mut seen = vec![0; state.disks.len()];
+        for (cell_id, cell) in state.grid.cells.iter().enumerate() {
+            for disk_id in cell.disk_ids.iter() {
+                assert_eq!(state.disks[*disk_id].cell_id, cell_id);
+                seen[*disk_id] += 1;
+            }
+        }
+        assert!(seen.iter().all(|count| *count == 1));
+        assert!(!state.are_any_disks_overlapping());
+    }
+
+    #[test]
+    fn test_scale_box_keeps_displacements() {
+        let mut state = State::hexagonal_packing(4, 4, 0.3);
+        let x = state.disks[15].position.x;
+        state.scale_box(true, 1.2);
+        state.scale_box(false, 0.9);
+        state.update_grid();
+        assert!((state.disks[15].position.x - 1.2 * x).abs() < 1e-12);
+        assert!(state
+            .disks
+            .iter()
+            .all(|disk| disk.displacement.x == 0.0 && disk.displacement.y == 0.0));
+        assert!(!state.are_any_disks_overlapping());
+    }
+
+    #[test]
+    #[should_panic(expected = "Line 3 of")]
+    fn test_coords_file_short_line() {
+        let filepath = std::env::temp_dir().join("hard-disks-test-short-line.txt");
+        std::fs::write(&filepath, "2 0.5 10.0 10.0\n1.0 1.0\n3.0\n").unwrap();
+        let result = std::panic::catch_unwind(|| State::from_coords_file(&filepath));
+        std::fs::remove_file(&filepath).unwrap();
+        std::panic::resume_unwind(result.err().unwrap());
+    }
+}
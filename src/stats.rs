@@ -0,0 +1,269 @@
+use serde::{Deserialize, Serialize};
+
+use crate::thermo;
+
+// Mean of a correlated time series with its error bar, from the
+// Flyvbjerg-Petersen blocking transformation
+#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+pub struct BlockingAnalysis {
+    pub value: f64,
+    pub error: f64,
+    // In samples, 1/2 for uncorrelated data
+    pub autocorrelation_time: f64,
+    pub effective_sample_size: f64,
+    pub number_of_samples: usize,
+    // False when the series is too short for the error to plateau, the
+    // error is then a lower bound
+    pub converged: bool,
+}
+
+impl BlockingAnalysis {
+    pub fn estimate(&self) -> thermo::Estimate {
+        thermo::Estimate {
+            value: self.value,
+            error: self.error,
+        }
+    }
+}
+
+// Error of the mean at each blocking level, pairs of consecutive values are
+// averaged from one level to the next
+fn blocking_errors(series: &[f64]) -> Vec<f64> {
+    let mut errors: Vec<f64> = Vec::new();
+    let mut values = series.to_vec();
+    while values.len() >= 2 {
+        let n = values.len() as f64;
+        let mean = values.iter().sum::<f64>() / n;
+        let variance = values.iter().map(|v| (v - mean) * (v - mean)).sum::<f64>() / (n - 1.0);
+        errors.push((variance / n).sqrt());
+        values = values
+            .chunks_exact(2)
+            .map(|pair| 0.5 * (pair[0] + pair[1]))
+            .collect();
+    }
+    errors
+}
+
+pub fn blocking_analysis(series: &[f64]) -> Option<BlockingAnalysis> {
+    if series.len() < 2 {
+        return None;
+    }
+    let n = series.len();
+    let mean = series.iter().sum::<f64>() / n as f64;
+    let errors = blocking_errors(series);
+    let naive_error = errors[0];
+    if naive_error == 0.0 {
+        return Some(BlockingAnalysis {
+            value: mean,
+            error: 0.0,
+            autocorrelation_time: 0.5,
+            effective_sample_size: n as f64,
+            number_of_samples: n,
+            converged: true,
+        });
+    }
+
+    // First block size B with B^3 > 2 n (error_B / error_0)^4, from Lee,
+    // Morales and Umrigar, Phys. Rev. E 84, 066701 (2011)
+    let mut optimal_level: Option<usize> = None;
+    for (level, error) in errors.iter().enumerate() {
+        let block_size = 2f64.powi(level as i32);
+        if block_size.powi(3) > 2.0 * n as f64 * (error / naive_error).powi(4) {
+            optimal_level = Some(level);
+            break;
+        }
+    }
+    let converged = optimal_level.is_some();
+    // Largest level with a few blocks left otherwise
+    let level = optimal_level.unwrap_or(errors.len().saturating_sub(3));
+    let error = errors[level].max(naive_error);
+    let ratio = (error / naive_error).powi(2);
+    Some(BlockingAnalysis {
+        value: mean,
+        error,
+        autocorrelation_time: 0.5 * ratio,
+        effective_sample_size: n as f64 / ratio,
+        number_of_samples: n,
+        converged,
+    })
+}
+
+// Number of leading samples to drop, with the marginal standard error rule
+// (White, Simulation 69, 323 (1997)) on the means of batches of batch_size
+// samples. The cut is never beyond half the series.
+pub fn mser_truncation(series: &[f64], batch_size: usize) -> usize {
+    let batches: Vec<f64> = series
+        .chunks_exact(batch_size)
+        .map(|batch| batch.iter().sum::<f64>() / batch_size as f64)
+        .collect();
+    let k = batches.len();
+    if k < 2 {
+        return 0;
+    }
+    // Constant up to rounding errors, e.g. the density of NVT runs
+    let max = batches.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+    let min = batches.iter().copied().fold(f64::INFINITY, f64::min);
+    if max - min <= 1e-12 * max.abs().max(min.abs()) {
+        return 0;
+    }
+    // Sums over batches[d..], built from the end
+    let mut sum = 0.0;
+    let mut sum_sq = 0.0;
+    let mut suffix_sums: Vec<(f64, f64)> = vec![(0.0, 0.0); k];
+    for d in (0..k).rev() {
+        sum += batches[d];
+        sum_sq += batches[d] * batches[d];
+        suffix_sums[d] = (sum, sum_sq);
+    }
+    let mut best_cut = 0;
+    let mut best_mser = f64::INFINITY;
+    for (d, (sum, sum_sq)) in suffix_sums.iter().enumerate().take(k / 2 + 1) {
+        let n = (k - d) as f64;
+        // Σ (x - mean)² / n²
+        let mser = (sum_sq - sum * sum / n).max(0.0) / (n * n);
+        if mser < best_mser {
+            best_mser = mser;
+            best_cut = d;
+        }
+    }
+    best_cut * batch_size
+}
+
+// Running sums of vectors, kept per block of consecutive samples so that the
+// samples before the equilibration cut can be dropped at the end of the run.
+// Pairs of blocks are merged when there are too many, the cut is then
+// rounded up to the next block.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct BlockSums {
+    length: usize,
+    block_size: usize,
+    blocks: Vec<Vec<f64>>,
+}
+
+const MAX_SUM_BLOCKS: usize = 64;
+
+impl BlockSums {
+    pub fn new(length: usize) -> BlockSums {
+        BlockSums {
+            length,
+            block_size: 1,
+            blocks: Vec::new(),
+        }
+    }
+
+    pub fn add(&mut self, sample_id: usize, values: &[f64]) {
+        while sample_id / self.block_size >= MAX_SUM_BLOCKS {
+            self.merge_pairs();
+        }
+        let block_id = sample_id / self.block_size;
+        while self.blocks.len() <= block_id {
+            self.blocks.push(vec![0.0; self.length]);
+        }
+        for (sum, value) in self.blocks[block_id].iter_mut().zip(values.iter()) {
+            *sum += value;
+        }
+    }
+
+    fn merge_pairs(&mut self) {
+        let mut blocks: Vec<Vec<f64>> = Vec::new();
+        for pair in self.blocks.chunks(2) {
+            let mut block = pair[0].clone();
+            if pair.len() == 2 {
+                for (sum, value) in block.iter_mut().zip(pair[1].iter()) {
+                    *sum += value;
+                }
+            }
+            blocks.push(block);
+        }
+        self.blocks = blocks;
+        self.block_size *= 2;
+    }
+
+    // Drop every block that starts before the cut, but the last one, and
+    // merge the others: the sums are final afterwards. Returns the number of
+    // samples dropped.
+    pub fn discard_samples(&mut self, n_samples: usize) -> usize {
+        let n_blocks = n_samples
+            .div_ceil(self.block_size)
+            .min(self.blocks.len().saturating_sub(1));
+        self.blocks.drain(..n_blocks);
+        self.blocks = vec![self.total()];
+        n_blocks * self.block_size
+    }
+
+    pub fn total(&self) -> Vec<f64> {
+        let mut total = vec![0.0; self.length];
+        for block in self.blocks.iter() {
+            for (sum, value) in total.iter_mut().zip(block.iter()) {
+                *sum += value;
+            }
+        }
+        total
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sample;
+    use rand::Rng;
+
+    #[test]
+    fn test_blocking_analysis() {
+        let (mut rng, _) = sample::create_rng(Some(42));
+        let n = 1 << 16;
+
+        let uncorrelated: Vec<f64> = (0..n).map(|_| rng.gen::<f64>()).collect();
+        let analysis = blocking_analysis(&uncorrelated).unwrap();
+        assert!(analysis.converged);
+        assert!((analysis.autocorrelation_time - 0.5).abs() < 0.15);
+        // Variance of a uniform variable is 1/12
+        let expected_error = (1.0 / 12.0 / n as f64).sqrt();
+        assert!((analysis.error / expected_error - 1.0).abs() < 0.3);
+
+        // AR(1) process, τ = (1 + ρ) / 2(1 - ρ)
+        let rho = 0.9;
+        let mut value = 0.0;
+        let correlated: Vec<f64> = (0..n)
+            .map(|_| {
+                value = rho * value + rng.gen::<f64>() - 0.5;
+                value
+            })
+            .collect();
+        let analysis = blocking_analysis(&correlated).unwrap();
+        assert!(analysis.converged);
+        let expected_time = (1.0 + rho) / (2.0 * (1.0 - rho));
+        assert!((analysis.autocorrelation_time / expected_time - 1.0).abs() < 0.3);
+    }
+
+    #[test]
+    fn test_mser_truncation() {
+        let (mut rng, _) = sample::create_rng(Some(42));
+        // Exponential relaxation over the first ~200 samples
+        let series: Vec<f64> = (0..2000)
+            .map(|i| 5.0 * (-(i as f64) / 50.0).exp() + rng.gen::<f64>())
+            .collect();
+        let cut = mser_truncation(&series, 5);
+        assert!(cut > 100 && cut < 400);
+
+        let stationary: Vec<f64> = (0..2000).map(|_| rng.gen::<f64>()).collect();
+        assert!(mser_truncation(&stationary, 5) < 200);
+    }
+
+    #[test]
+    fn test_block_sums() {
+        let mut sums = BlockSums::new(2);
+        for sample_id in 0..1000 {
+            sums.add(sample_id, &[1.0, sample_id as f64]);
+        }
+        assert_eq!(sums.total(), vec![1000.0, 999.0 * 1000.0 / 2.0]);
+        // Blocks of 16 samples after the merges, the cut goes up to 208
+        let discarded = sums.discard_samples(200);
+        assert_eq!(discarded, 208);
+        let kept = (1000 - discarded) as f64;
+        assert_eq!(
+            sums.total(),
+            vec![kept, (discarded as f64 + 999.0) * kept / 2.0]
+        );
+    }
+}
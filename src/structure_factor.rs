@@ -0,0 +1,180 @@
+use serde::{Deserialize, Serialize};
+use std::f64::consts::PI;
+
+use crate::state;
+use crate::stats;
+
+// S(k) = |Σ_j exp(-i k.r_j)|² / N on the wave vectors allowed by the box,
+// k = 2π (n / lx, m / ly)
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StructureFactor {
+    // Radial average
+    pub k: Vec<f64>,
+    pub s_of_k: Vec<f64>,
+    // Full 2D map, one entry per wave vector
+    pub kx: Vec<f64>,
+    pub ky: Vec<f64>,
+    pub s_of_k_map: Vec<f64>,
+    k_max: f64,
+    dk: f64,
+    n_max: i64,
+    m_max: i64,
+    counter: u32,
+    // Sums of S and counts for each k bin, then S, kx and ky for each wave
+    // vector of the half plane m > 0 or m = 0 and n > 0, since S(-k) = S(k).
+    // The last one counts the updates.
+    sums: stats::BlockSums,
+    n_wave_vectors: usize,
+}
+
+impl StructureFactor {
+    pub fn new(state: &state::State, k_max: f64, bin_width: f64) -> StructureFactor {
+        let n_points = (k_max / bin_width).floor() as usize;
+        let mut k: Vec<f64> = Vec::new();
+        for i in 0..n_points {
+            k.push((i as f64 + 0.5) * bin_width);
+        }
+        let n_max = (k_max * state.sim_box.lx / (2.0 * PI)).floor() as i64;
+        let m_max = (k_max * state.sim_box.ly / (2.0 * PI)).floor() as i64;
+        let n_wave_vectors = half_plane_indices(n_max, m_max).len();
+        StructureFactor {
+            k,
+            s_of_k: vec![0.0; n_points],
+            kx: Vec::new(),
+            ky: Vec::new(),
+            s_of_k_map: Vec::new(),
+            k_max: n_points as f64 * bin_width,
+            dk: bin_width,
+            n_max,
+            m_max,
+            counter: 0,
+            sums: stats::BlockSums::new(2 * n_points + 3 * n_wave_vectors + 1),
+            n_wave_vectors,
+        }
+    }
+
+    pub fn update(&mut self, state: &state::State) {
+        let indices = half_plane_indices(self.n_max, self.m_max);
+        let width = (2 * self.n_max + 1) as usize;
+        // Real and imaginary parts of Σ_j exp(-i k.r_j), indexed by
+        // (n + n_max) + width * m
+        let mut rho_re = vec![0.0; width * (self.m_max + 1) as usize];
+        let mut rho_im = vec![0.0; width * (self.m_max + 1) as usize];
+        let mut phase_x_re = vec![0.0; width];
+        let mut phase_x_im = vec![0.0; width];
+        for disk in state.disks.iter() {
+            let angle_x = -2.0 * PI * disk.position.x / state.sim_box.lx;
+            let angle_y = -2.0 * PI * disk.position.y / state.sim_box.ly;
+            for n in -self.n_max..=self.n_max {
+                let angle = n as f64 * angle_x;
+                phase_x_re[(n + self.n_max) as usize] = angle.cos();
+                phase_x_im[(n + self.n_max) as usize] = angle.sin();
+            }
+            for m in 0..=self.m_max {
+                let (sin_y, cos_y) = (m as f64 * angle_y).sin_cos();
+                for id_x in 0..width {
+                    let id = id_x + width * m as usize;
+                    rho_re[id] += phase_x_re[id_x] * cos_y - phase_x_im[id_x] * sin_y;
+                    rho_im[id] += phase_x_re[id_x] * sin_y + phase_x_im[id_x] * cos_y;
+                }
+            }
+        }
+
+        let n_disks = state.disks.len() as f64;
+        let (n_points, n_wave_vectors) = (self.k.len(), self.n_wave_vectors);
+        let map_offset = 2 * n_points;
+        let mut values = vec![0.0; 2 * n_points + 3 * n_wave_vectors + 1];
+        for (index, (n, m)) in indices.iter().enumerate() {
+            let id = (n + self.n_max) as usize + width * *m as usize;
+            let s = (rho_re[id] * rho_re[id] + rho_im[id] * rho_im[id]) / n_disks;
+            let kx = 2.0 * PI * *n as f64 / state.sim_box.lx;
+            let ky = 2.0 * PI * *m as f64 / state.sim_box.ly;
+            values[map_offset + index] = s;
+            values[map_offset + n_wave_vectors + index] = kx;
+            values[map_offset + 2 * n_wave_vectors + index] = ky;
+            let k = (kx * kx + ky * ky).sqrt();
+            if k < self.k_max {
+                let bin = (k / self.dk).floor() as usize;
+                values[bin] += s;
+                values[n_points + bin] += 1.0;
+            }
+        }
+        *values.last_mut().unwrap() = 1.0;
+        self.sums.add(self.counter as usize, &values);
+        self.counter += 1;
+    }
+
+    pub fn discard_samples(&mut self, n_samples: usize) {
+        self.sums.discard_samples(n_samples);
+    }
+
+    pub fn finalize(&mut self) {
+        let sums = self.sums.total();
+        let counter = *sums.last().unwrap();
+        if counter == 0.0 {
+            return;
+        }
+        let (n_points, n_wave_vectors) = (self.k.len(), self.n_wave_vectors);
+        for bin in 0..n_points {
+            if sums[n_points + bin] > 0.0 {
+                self.s_of_k[bin] = sums[bin] / sums[n_points + bin];
+            }
+        }
+        self.kx.clear();
+        self.ky.clear();
+        self.s_of_k_map.clear();
+        let map_offset = 2 * n_points;
+        for index in 0..n_wave_vectors {
+            let s = sums[map_offset + index] / counter;
+            let kx = sums[map_offset + n_wave_vectors + index] / counter;
+            let ky = sums[map_offset + 2 * n_wave_vectors + index] / counter;
+            // Both k and -k, for a symmetric diffraction pattern
+            self.kx.extend([kx, -kx]);
+            self.ky.extend([ky, -ky]);
+            self.s_of_k_map.extend([s, s]);
+        }
+    }
+}
+
+// Wave vectors up to the sign, without k = 0
+fn half_plane_indices(n_max: i64, m_max: i64) -> Vec<(i64, i64)> {
+    let mut indices: Vec<(i64, i64)> = Vec::new();
+    for m in 0..=m_max {
+        for n in -n_max..=n_max {
+            if m > 0 || n > 0 {
+                indices.push((n, m));
+            }
+        }
+    }
+    indices
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bragg_peak() {
+        let state = state::State::hexagonal_packing(8, 8, 0.7);
+        // Rows are shifted by half a lattice spacing, so the first peak
+        // along x is at twice 2π / a
+        let k_bragg = 2.0 * PI * 16.0 / state.sim_box.lx;
+        let mut structure_factor = StructureFactor::new(&state, 1.1 * k_bragg, 0.1);
+        structure_factor.update(&state);
+        structure_factor.finalize();
+        let mut found_peak = false;
+        for index in 0..structure_factor.kx.len() {
+            let kx = structure_factor.kx[index];
+            let ky = structure_factor.ky[index];
+            let s = structure_factor.s_of_k_map[index];
+            if (kx.abs() - k_bragg).abs() < 1e-10 && ky == 0.0 {
+                assert!((s - 64.0).abs() < 1e-8);
+                found_peak = true;
+            }
+            if (kx.abs() - k_bragg / 2.0).abs() < 1e-10 && ky == 0.0 {
+                assert!(s < 1e-8);
+            }
+        }
+        assert!(found_peak);
+    }
+}
@@ -1,39 +1,416 @@
-use crate::{geometry, state};
+use crate::{
+    config, defects, dynamics, geometry, order, sample, state, stats, structure_factor, widom,
+};
+use rand::Rng;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::f64::consts::PI;
 use std::fs::File;
 use std::path::Path;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Thermo {
+    pub seed: u64,
     pub step: Vec<u32>,
     pub density: Vec<f64>,
     pub nvt_acceptance_rate: f64,
     pub npt_acceptance_rate: f64,
+    pub max_displacement: f64,
+    // Only for NPT runs
+    pub max_volume_change: f64,
+    // Only for μVT runs
+    pub insertion_acceptance_rate: f64,
+    pub deletion_acceptance_rate: f64,
+    // Trial moves spent with each number of disks, only for μVT runs
+    pub number_of_disks_histogram: Vec<u64>,
     pub g_of_r: GofRlowR,
+    // One per pair of species, only for mixtures
+    pub partial_g_of_r: Vec<GofRlowR>,
+    // From the contact values, or the lifts of event chains, with the error
+    // of the blocking analysis in statistics
+    pub pressure: Option<Estimate>,
+    // Block means of the event-chain pressure, copied from the simulation
+    // for the analysis at the end of the run. Only the estimate is reported.
+    #[serde(skip)]
+    pub pressure_block_means: Vec<f64>,
+    pub radial_distribution: Option<GofR>,
+    pub psi6: Option<order::Psi6Series>,
+    pub g6_of_r: Option<order::G6ofR>,
+    pub defects: Option<defects::DefectSeries>,
+    pub structure_factor: Option<structure_factor::StructureFactor>,
+    pub dynamics: Option<dynamics::Dynamics>,
+    pub widom: Option<widom::WidomInsertion>,
+    // βμ_ex from the Widom insertions
+    pub excess_chemical_potential: Option<Estimate>,
+    // Blocking analysis of each scalar time series
+    pub statistics: BTreeMap<String, stats::BlockingAnalysis>,
+    pub equilibration: Option<Equilibration>,
+}
+
+// Transient detected with MSER at the end of the run. It is removed from the
+// time series and from every observable kept as a sum. Those are stored in
+// blocks of samples (of time origins for the dynamics), and drop every block
+// that starts before the cut.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Equilibration {
+    pub discarded_samples: usize,
+    // First step that is kept
+    pub first_step: u32,
+    // Samples to drop according to each series, the largest cut is used
+    pub cuts: BTreeMap<String, usize>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+pub struct Estimate {
+    pub value: f64,
+    pub error: f64,
+}
+
+// Running average over consecutive blocks of samples, the spread of the block
+// means gives the error bar
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BlockAverage {
+    pub block_size: u32,
+    pub block_means: Vec<f64>,
+    current_sum: f64,
+    current_count: u32,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct GofRlowR {
+    // Pair of species this is restricted to, all the pairs otherwise
+    pub species: Option<[u32; 2]>,
     pub r: Vec<f64>,
     pub g: Vec<u32>,
     counter: u32,
     r_max: f64,
     dr: f64,
     normalized_g: Vec<f64>,
+    pub contact_value: Option<Estimate>,
+    // Histograms accumulated over consecutive blocks of updates, resampled
+    // to get the error bars
+    blocks: Vec<GofRBlock>,
+    block_size: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct GofRBlock {
+    counter: u32,
+    g: Vec<u32>,
 }
 
 impl Thermo {
+    #[allow(clippy::needless_return)]
     pub fn empty_thermo() -> Thermo {
         return Thermo {
+            seed: 0,
             step: Vec::new(),
             density: Vec::new(),
             nvt_acceptance_rate: 0.0,
             npt_acceptance_rate: 0.0,
+            max_displacement: 0.0,
+            max_volume_change: 0.0,
+            insertion_acceptance_rate: 0.0,
+            deletion_acceptance_rate: 0.0,
+            number_of_disks_histogram: Vec::new(),
             g_of_r: GofRlowR::empty_g_of_r(),
+            partial_g_of_r: Vec::new(),
+            pressure: None,
+            pressure_block_means: Vec::new(),
+            radial_distribution: None,
+            psi6: None,
+            g6_of_r: None,
+            defects: None,
+            structure_factor: None,
+            dynamics: None,
+            widom: None,
+            excess_chemical_potential: None,
+            statistics: BTreeMap::new(),
+            equilibration: None,
         };
     }
 
+    pub fn initialize_observables(
+        &mut self,
+        config: &config::Config,
+        state: &state::State,
+        rng: &sample::SimulationRng,
+    ) {
+        if let Some(rdf_config) = &config.radial_distribution {
+            let half_box = state.sim_box.lx.min(state.sim_box.ly) / 2.0;
+            let r_max = rdf_config.r_max.unwrap_or(half_box).min(half_box);
+            self.radial_distribution = Some(GofR::new(r_max, rdf_config.bin_width));
+        }
+        if let Some(psi6_config) = &config.psi6 {
+            let cutoff = psi6_config
+                .cutoff
+                .unwrap_or(1.4 * 2.0 * state.get_mean_radius());
+            self.psi6 = Some(order::Psi6Series::new(psi6_config.neighbors, cutoff));
+        }
+        if let Some(g6_config) = &config.g6_of_r {
+            let cutoff = g6_config
+                .cutoff
+                .unwrap_or(1.4 * 2.0 * state.get_mean_radius());
+            let half_box = state.sim_box.lx.min(state.sim_box.ly) / 2.0;
+            let r_max = g6_config.r_max.unwrap_or(half_box).min(half_box);
+            self.g6_of_r = Some(order::G6ofR::new(
+                g6_config.neighbors,
+                cutoff,
+                r_max,
+                g6_config.bin_width,
+            ));
+        }
+        if config.defects {
+            self.defects = Some(defects::DefectSeries::default());
+        }
+        if let Some(sk_config) = &config.structure_factor {
+            self.structure_factor = Some(structure_factor::StructureFactor::new(
+                state,
+                sk_config.k_max,
+                sk_config.bin_width,
+            ));
+        }
+        if let Some(dynamics_config) = &config.dynamics {
+            self.dynamics = Some(dynamics::Dynamics::new(
+                dynamics_config.k.unwrap_or(PI / state.get_mean_radius()),
+                dynamics_config.max_lag,
+                dynamics_config.points_per_decade.unwrap_or(10),
+            ));
+        }
+        if let Some(widom_config) = &config.widom {
+            self.widom = Some(widom::WidomInsertion::new(
+                widom_config.radius.unwrap_or(state.get_mean_radius()),
+                widom_config.insertions,
+                rng,
+            ));
+        }
+    }
+
+    // Called by every sampler, once per sampling interval
+    pub fn sample_observables(&mut self, state: &mut state::State, step: u32) {
+        self.step.push(step);
+        self.density.push(state.get_density());
+        if let Some(radial_distribution) = &mut self.radial_distribution {
+            radial_distribution.update(state);
+        }
+        if let Some(psi6) = &mut self.psi6 {
+            psi6.update(state, step);
+        }
+        if let Some(g6_of_r) = &mut self.g6_of_r {
+            g6_of_r.update(state);
+        }
+        if let Some(defects) = &mut self.defects {
+            defects.update(state, step);
+        }
+        if let Some(structure_factor) = &mut self.structure_factor {
+            structure_factor.update(state);
+        }
+        if let Some(dynamics) = &mut self.dynamics {
+            dynamics.update(state, step);
+        }
+        if let Some(widom) = &mut self.widom {
+            widom.update(state, step);
+        }
+    }
+
+    pub fn finalize_observables(&mut self) {
+        self.analyze_time_series();
+        if let Some(radial_distribution) = &mut self.radial_distribution {
+            radial_distribution.finalize();
+        }
+        if let Some(g6_of_r) = &mut self.g6_of_r {
+            g6_of_r.finalize();
+        }
+        if let Some(structure_factor) = &mut self.structure_factor {
+            structure_factor.finalize();
+        }
+        if let Some(dynamics) = &mut self.dynamics {
+            dynamics.finalize();
+        }
+    }
+
+    // Has to be called before finalize_g_of_r and finalize_observables
+    pub fn detect_equilibration(&mut self, state: &state::State, fit: config::ContactFit) {
+        // MSER-5 on long series, the g(r) blocks are already averages
+        let batch_size = |length: usize| if length >= 100 { 5 } else { 1 };
+        let mut cuts: BTreeMap<String, usize> = BTreeMap::new();
+        if self.density.len() >= 2 {
+            let cut = stats::mser_truncation(&self.density, batch_size(self.density.len()));
+            cuts.insert("density".to_string(), cut);
+        }
+        let block_means = &self.pressure_block_means;
+        if !block_means.is_empty() {
+            let cut = stats::mser_truncation(block_means, batch_size(block_means.len()));
+            cuts.insert("pressure".to_string(), cut);
+        } else if self.g_of_r.blocks.len() >= 2 {
+            // The contact value is an affine function of the pressure
+            let contact_values = self.g_of_r.block_contact_values(state, fit);
+            let cut = stats::mser_truncation(&contact_values, 1);
+            cuts.insert(
+                "pressure".to_string(),
+                cut * self.g_of_r.block_size as usize,
+            );
+        }
+        let discarded_samples = cuts.values().copied().max().unwrap_or(0);
+
+        // Every g(r) block that starts before the cut, keeping two for the
+        // error bar
+        let block_size = self.g_of_r.block_size as usize;
+        let n_blocks = discarded_samples
+            .div_ceil(block_size)
+            .min(self.g_of_r.blocks.len().saturating_sub(2));
+        self.g_of_r.discard_blocks(n_blocks);
+        for partial in self.partial_g_of_r.iter_mut() {
+            partial.discard_blocks(n_blocks);
+        }
+        if let Some(radial_distribution) = &mut self.radial_distribution {
+            radial_distribution.discard_samples(discarded_samples);
+        }
+        if let Some(g6_of_r) = &mut self.g6_of_r {
+            g6_of_r.discard_samples(discarded_samples);
+        }
+        if let Some(structure_factor) = &mut self.structure_factor {
+            structure_factor.discard_samples(discarded_samples);
+        }
+        if let Some(dynamics) = &mut self.dynamics {
+            dynamics.discard_samples(discarded_samples);
+        }
+
+        self.equilibration = Some(Equilibration {
+            discarded_samples,
+            first_step: *self
+                .step
+                .get(discarded_samples)
+                .or(self.step.last())
+                .unwrap_or(&0),
+            cuts,
+        });
+    }
+
+    fn analyze_time_series(&mut self) {
+        let cut = self
+            .equilibration
+            .as_ref()
+            .map_or(0, |equilibration| equilibration.discarded_samples);
+        let mut series: Vec<(&str, &[f64])> = vec![("density", &self.density)];
+        // Event-chain runs, one value per block of chains
+        if !self.pressure_block_means.is_empty() {
+            series.push(("pressure", &self.pressure_block_means));
+        }
+        if let Some(psi6) = &self.psi6 {
+            series.push(("psi6", &psi6.psi6));
+        }
+        if let Some(defects) = &self.defects {
+            series.push(("dislocations", &defects.dislocations));
+            series.push(("free_disclinations", &defects.free_disclinations));
+        }
+        for (name, values) in series.iter() {
+            if let Some(analysis) = stats::blocking_analysis(&values[cut.min(values.len())..]) {
+                self.statistics.insert(name.to_string(), analysis);
+            }
+        }
+        // Weighted by the volume, for NPT runs
+        if let Some(widom) = &self.widom {
+            let weighted = widom.weighted_insertion_probability(cut);
+            if let Some(analysis) = stats::blocking_analysis(&weighted) {
+                self.statistics
+                    .insert("insertion_probability".to_string(), analysis);
+            }
+        }
+        if let Some(pressure) = self.statistics.get("pressure") {
+            self.pressure = Some(pressure.estimate());
+        }
+        // Undefined when no ghost ever fitted
+        if let Some(probability) = self.statistics.get("insertion_probability") {
+            if probability.value > 0.0 {
+                self.excess_chemical_potential = Some(Estimate {
+                    value: -probability.value.ln(),
+                    error: probability.error / probability.value,
+                });
+            }
+        }
+    }
+
+    pub fn initialize_g_of_r(&mut self, state: &state::State, block_size: u32) {
+        self.g_of_r
+            .initialize_vectors(state.get_mean_radius(), block_size);
+        let species = state.get_species();
+        if species.len() < 2 {
+            return;
+        }
+        for a in 0..species.len() {
+            for b in a..species.len() {
+                let mut partial = GofRlowR::empty_g_of_r();
+                partial.species = Some([species[a], species[b]]);
+                // Contact at the mean of r_i + r_j for this pair of species
+                let contact = state.get_mean_radius_of_species(species[a])
+                    + state.get_mean_radius_of_species(species[b]);
+                partial.initialize_vectors(contact / 2.0, block_size);
+                self.partial_g_of_r.push(partial);
+            }
+        }
+    }
+
+    pub fn update_g_of_r(&mut self, state: &state::State) {
+        self.g_of_r.update(state);
+        for partial in self.partial_g_of_r.iter_mut() {
+            partial.update(state);
+        }
+    }
+
+    // Normalize the g(r) and compute the contact values, with error bars from
+    // a bootstrap over the blocks. The pressure is the only one of the run,
+    // with a blocking analysis over the g(r) blocks as they are correlated.
+    // For mixtures it comes from the partial g_ab(r),
+    // βP = ρ + π/2 Σ_a Σ_b ρ_a ρ_b σ_ab² g_ab(σ_ab+)
+    pub fn finalize_g_of_r<R: Rng>(
+        &mut self,
+        state: &state::State,
+        fit: config::ContactFit,
+        rng: &mut R,
+    ) {
+        self.g_of_r.renormalize(state);
+        if self.g_of_r.counter == 0 {
+            return;
+        }
+        // The same blocks are drawn for every pair, as they are correlated
+        let resamples = bootstrap_resamples(self.g_of_r.blocks.len(), rng);
+        self.g_of_r.compute_contact_value(state, fit, &resamples);
+        for partial in self.partial_g_of_r.iter_mut() {
+            partial.renormalize(state);
+            partial.compute_contact_value(state, fit, &resamples);
+        }
+        // Event-chain runs use the lifts instead
+        if self.pressure_block_means.is_empty() {
+            let block_pressures = self.block_contact_pressures(state, fit);
+            if let Some(analysis) = stats::blocking_analysis(&block_pressures) {
+                self.statistics.insert("pressure".to_string(), analysis);
+            }
+        }
+    }
+
+    // βP of each g(r) block on its own
+    fn block_contact_pressures(&self, state: &state::State, fit: config::ContactFit) -> Vec<f64> {
+        if self.partial_g_of_r.is_empty() {
+            return self
+                .g_of_r
+                .block_contact_values(state, fit)
+                .iter()
+                .map(|contact| self.g_of_r.pressure_from_contact(state, *contact))
+                .collect();
+        }
+        let mut pressures = vec![state.get_number_density(); self.g_of_r.blocks.len()];
+        for partial in self.partial_g_of_r.iter() {
+            let prefactor = partial.contact_prefactor(state);
+            let contacts = partial.block_contact_values(state, fit);
+            for k in 0..pressures.len() {
+                pressures[k] += prefactor * contacts[k];
+            }
+        }
+        pressures
+    }
+
+    #[allow(clippy::needless_borrows_for_generic_args)]
     pub fn to_yaml(&self, filepath: &Path) {
         let file = File::create(&filepath).unwrap();
         //let f = std::fs::OpenOptions::new()
@@ -45,19 +422,64 @@ impl Thermo {
     }
 }
 
+impl BlockAverage {
+    pub fn new(block_size: u32) -> BlockAverage {
+        BlockAverage {
+            block_size,
+            block_means: Vec::new(),
+            current_sum: 0.0,
+            current_count: 0,
+        }
+    }
+
+    pub fn add(&mut self, value: f64) {
+        self.current_sum += value;
+        self.current_count += 1;
+        if self.current_count == self.block_size {
+            self.block_means
+                .push(self.current_sum / self.current_count as f64);
+            self.current_sum = 0.0;
+            self.current_count = 0;
+        }
+    }
+
+    pub fn estimate(&self) -> Option<Estimate> {
+        let n_blocks = self.block_means.len();
+        if n_blocks < 2 {
+            return None;
+        }
+        let mean = self.block_means.iter().sum::<f64>() / n_blocks as f64;
+        let variance = self
+            .block_means
+            .iter()
+            .map(|value| (value - mean) * (value - mean))
+            .sum::<f64>()
+            / (n_blocks - 1) as f64;
+        Some(Estimate {
+            value: mean,
+            error: (variance / n_blocks as f64).sqrt(),
+        })
+    }
+}
+
 impl GofRlowR {
+    #[allow(clippy::needless_return)]
     pub fn empty_g_of_r() -> GofRlowR {
         return GofRlowR {
+            species: None,
             r: Vec::new(),
             g: Vec::new(),
             counter: 0,
             r_max: 0.0,
             dr: 0.0,
             normalized_g: Vec::new(),
+            contact_value: None,
+            blocks: Vec::new(),
+            block_size: 1,
         };
     }
 
-    pub fn initialize_vectors(&mut self, radius: f64) {
+    pub fn initialize_vectors(&mut self, radius: f64, block_size: u32) {
         // Go from (r - 2 sigma) / sigma = 0 to 0.1
         // (r_max - 2 sigma) = 0.1 sigma; r_max = 2.1 sigma
         // r_min = 2 sigma
@@ -73,37 +495,358 @@ impl GofRlowR {
         self.counter = 0;
         self.r_max = r - dr / 2.0;
         self.dr = dr;
+        self.blocks = Vec::new();
+        self.block_size = block_size.max(1);
     }
 
+    // Distances are binned from the contact distance of each pair, so that
+    // polydisperse systems are handled. r is then relative to the mean contact.
     pub fn update(&mut self, state: &state::State) {
-        let sigma = state.disks[0].radius;
+        let width = self.r_max - (self.r[0] - self.dr / 2.0);
+        if self.counter.is_multiple_of(self.block_size) {
+            self.blocks.push(GofRBlock {
+                counter: 0,
+                g: vec![0; self.g.len()],
+            });
+        }
+        let block = self.blocks.last_mut().unwrap();
         for disk_id_i in 0..state.disks.len() {
             for disk_id_j in state.get_neighbor_disks(disk_id_i) {
+                if let Some([a, b]) = self.species {
+                    let species_i = state.disks[disk_id_i].species;
+                    let species_j = state.disks[disk_id_j].species;
+                    if !((species_i == a && species_j == b) || (species_i == b && species_j == a)) {
+                        continue;
+                    }
+                }
                 let r_ij = geometry::distance_sq_periodic(
                     &state.disks[disk_id_i].position,
                     &state.disks[disk_id_j].position,
                     &state.sim_box,
                 )
                 .sqrt();
-                if r_ij < self.r_max {
-                    let g_id = ((r_ij - 2.0 * sigma) / self.dr).floor() as u32;
+                let sigma_ij = state.disks[disk_id_i].radius + state.disks[disk_id_j].radius;
+                if r_ij - sigma_ij < width {
+                    let g_id = ((r_ij - sigma_ij) / self.dr).floor() as u32;
                     self.g[g_id as usize] += 1;
+                    block.g[g_id as usize] += 1;
                 }
             }
         }
         self.counter += 1;
+        block.counter += 1;
+    }
+
+    pub fn number_of_blocks(&self) -> usize {
+        self.blocks.len()
+    }
+
+    // Forget the first blocks, e.g. the equilibration
+    fn discard_blocks(&mut self, n_blocks: usize) {
+        for block in self.blocks.drain(..n_blocks) {
+            for i in 0..self.g.len() {
+                self.g[i] -= block.g[i];
+            }
+            self.counter -= block.counter;
+        }
+    }
+
+    // Contact value of each block on its own
+    fn block_contact_values(&self, state: &state::State, fit: config::ContactFit) -> Vec<f64> {
+        self.blocks
+            .iter()
+            .map(|block| {
+                let normalized_g = self.normalize(&block.g, block.counter, state);
+                self.extrapolate_to_contact(&normalized_g, fit)
+            })
+            .collect()
     }
 
     pub fn renormalize(&mut self, state: &state::State) {
-        let number_density = state.get_number_density();
-        for i in 0..self.g.len() {
+        self.normalized_g = self.normalize(&self.g, self.counter, state);
+    }
+
+    // Number of (ordered) pairs per unit area for an ideal gas
+    fn pair_density(&self, state: &state::State) -> f64 {
+        let volume = state.sim_box.lx * state.sim_box.ly;
+        match self.species {
+            None => state.disks.len() as f64 * state.get_number_density(),
+            Some([a, b]) => {
+                let n_a = state.count_species(a) as f64;
+                let n_b = state.count_species(b) as f64;
+                if a == b {
+                    return n_a * n_a / volume;
+                }
+                2.0 * n_a * n_b / volume
+            }
+        }
+    }
+
+    fn normalize(&self, g: &[u32], counter: u32, state: &state::State) -> Vec<f64> {
+        let pair_density = self.pair_density(state);
+        let mut normalized_g = vec![0.0; g.len()];
+        for i in 0..g.len() {
             let r_low = self.r[i] - self.dr / 2.0;
             let r_high = r_low + self.dr;
             // surface area at that distance
             let surface_area = PI * (r_high * r_high - r_low * r_low);
+            let expected_value = surface_area * pair_density;
+
+            normalized_g[i] = g[i] as f64 / expected_value / counter as f64;
+        }
+        normalized_g
+    }
+
+    // Extrapolate g(r) to contact with a polynomial fit
+    fn extrapolate_to_contact(&self, normalized_g: &[f64], fit: config::ContactFit) -> f64 {
+        let degree = match fit {
+            config::ContactFit::Linear => 1,
+            config::ContactFit::Quadratic => 2,
+            config::ContactFit::Quartic => 4,
+        };
+        let r_contact = self.r[0] - self.dr / 2.0;
+        let width = self.r_max - r_contact;
+        // Work in reduced units, x = 0 at contact and 1 at r_max, for a well conditioned fit
+        let x: Vec<f64> = self.r.iter().map(|r| (r - r_contact) / width).collect();
+        let coefficients = polynomial_fit(&x, normalized_g, degree);
+        coefficients[0]
+    }
+
+    // The error bar comes from the bootstrap samples, each one is a list of
+    // block indices
+    fn compute_contact_value(
+        &mut self,
+        state: &state::State,
+        fit: config::ContactFit,
+        resamples: &[Vec<usize>],
+    ) {
+        let contact = self.extrapolate_to_contact(&self.normalized_g, fit);
+        let mut resampled_contacts: Vec<f64> = Vec::new();
+        for resample in resamples.iter() {
+            let mut g = vec![0; self.g.len()];
+            let mut counter = 0;
+            for block_id in resample.iter() {
+                let block = &self.blocks[*block_id];
+                for (count, block_count) in g.iter_mut().zip(block.g.iter()) {
+                    *count += block_count;
+                }
+                counter += block.counter;
+            }
+            let normalized_g = self.normalize(&g, counter, state);
+            resampled_contacts.push(self.extrapolate_to_contact(&normalized_g, fit));
+        }
+        self.contact_value = Some(Estimate {
+            value: contact,
+            error: standard_deviation(&resampled_contacts),
+        });
+    }
+
+    // βP = ρ (1 + π/2 ρ <σ_ij²> g(σ+)), which is ρ (1 + 2 φ g(σ+)) for equal disks
+    fn pressure_from_contact(&self, state: &state::State, contact: f64) -> f64 {
+        let number_density = state.get_number_density();
+        // Average of (r_i + r_j)² over all pairs
+        let mean_radius = state.get_mean_radius();
+        let mean_radius_sq = state
+            .disks
+            .iter()
+            .map(|disk| disk.radius * disk.radius)
+            .sum::<f64>()
+            / state.disks.len() as f64;
+        let mean_contact_sq = 2.0 * mean_radius_sq + 2.0 * mean_radius * mean_radius;
+        number_density * (1.0 + PI / 2.0 * number_density * mean_contact_sq * contact)
+    }
+
+    // π/2 ρ_a ρ_b σ_ab², twice that for unlike species, the weight of
+    // g_ab(σ_ab+) in the pressure of a mixture
+    fn contact_prefactor(&self, state: &state::State) -> f64 {
+        let volume = state.sim_box.lx * state.sim_box.ly;
+        let [a, b] = self.species.unwrap();
+        let sigma_ab = self.r[0] - self.dr / 2.0;
+        let prefactor = PI / 2.0 * sigma_ab * sigma_ab * state.count_species(a) as f64 / volume
+            * state.count_species(b) as f64
+            / volume;
+        if a != b {
+            return 2.0 * prefactor;
+        }
+        prefactor
+    }
+}
+
+// g(r) over the full range, from all the pairs of disks
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GofR {
+    pub r: Vec<f64>,
+    pub g: Vec<f64>,
+    // Mean number of disks within r
+    pub coordination_number: Vec<f64>,
+    r_max: f64,
+    dr: f64,
+    counter: u32,
+    // Each update is normalized with the density at that time, for NPT runs.
+    // Sums of g for each bin, then of the density and of the updates.
+    sums: stats::BlockSums,
+}
+
+impl GofR {
+    pub fn new(r_max: f64, bin_width: f64) -> GofR {
+        let n_points = (r_max / bin_width).floor() as usize;
+        let mut r: Vec<f64> = Vec::new();
+        for i in 0..n_points {
+            r.push((i as f64 + 0.5) * bin_width);
+        }
+        GofR {
+            r,
+            g: vec![0.0; n_points],
+            coordination_number: vec![0.0; n_points],
+            r_max: n_points as f64 * bin_width,
+            dr: bin_width,
+            counter: 0,
+            sums: stats::BlockSums::new(n_points + 2),
+        }
+    }
+
+    pub fn update(&mut self, state: &state::State) {
+        // Beyond half the box, some pairs would be missed
+        let r_max = self.r_max.min(state.sim_box.lx.min(state.sim_box.ly) / 2.0);
+        let r_max_sq = r_max * r_max;
+        let mut histogram = vec![0; self.r.len()];
+        for i in 0..state.disks.len() {
+            for j in i + 1..state.disks.len() {
+                let r_sq = geometry::distance_sq_periodic(
+                    &state.disks[i].position,
+                    &state.disks[j].position,
+                    &state.sim_box,
+                );
+                if r_sq < r_max_sq {
+                    histogram[(r_sq.sqrt() / self.dr).floor() as usize] += 2;
+                }
+            }
+        }
+
+        let number_density = state.get_number_density();
+        let mut values = vec![0.0; self.r.len() + 2];
+        for k in 0..self.r.len() {
+            let r_low = k as f64 * self.dr;
+            let r_high = r_low + self.dr;
+            let surface_area = PI * (r_high * r_high - r_low * r_low);
             let expected_value = surface_area * number_density * state.disks.len() as f64;
+            values[k] = histogram[k] as f64 / expected_value;
+        }
+        values[self.r.len()] = number_density;
+        values[self.r.len() + 1] = 1.0;
+        self.sums.add(self.counter as usize, &values);
+        self.counter += 1;
+    }
+
+    pub fn discard_samples(&mut self, n_samples: usize) {
+        self.sums.discard_samples(n_samples);
+    }
+
+    pub fn finalize(&mut self) {
+        let sums = self.sums.total();
+        let counter = sums[self.r.len() + 1];
+        if counter == 0.0 {
+            return;
+        }
+        let number_density = sums[self.r.len()] / counter;
+        let mut coordination_number = 0.0;
+        for (k, sum) in sums.iter().take(self.r.len()).enumerate() {
+            self.g[k] = sum / counter;
+            let r_low = k as f64 * self.dr;
+            let r_high = r_low + self.dr;
+            coordination_number +=
+                number_density * self.g[k] * PI * (r_high * r_high - r_low * r_low);
+            self.coordination_number[k] = coordination_number;
+        }
+    }
+}
+
+// Block indices drawn with replacement, for each bootstrap sample
+fn bootstrap_resamples<R: Rng>(number_of_blocks: usize, rng: &mut R) -> Vec<Vec<usize>> {
+    let n_resamples = 200;
+    let mut resamples: Vec<Vec<usize>> = Vec::new();
+    if number_of_blocks > 1 {
+        for _ in 0..n_resamples {
+            resamples.push(
+                (0..number_of_blocks)
+                    .map(|_| rng.gen_range(0..number_of_blocks))
+                    .collect(),
+            );
+        }
+    }
+    resamples
+}
+
+fn standard_deviation(values: &[f64]) -> f64 {
+    if values.len() < 2 {
+        return 0.0;
+    }
+    let mean = values.iter().sum::<f64>() / values.len() as f64;
+    let variance = values
+        .iter()
+        .map(|value| (value - mean) * (value - mean))
+        .sum::<f64>()
+        / (values.len() - 1) as f64;
+    variance.sqrt()
+}
+
+// Least squares fit of y = c_0 + c_1 x + ... + c_d x^d, solved through the
+// normal equations
+fn polynomial_fit(x: &[f64], y: &[f64], degree: usize) -> Vec<f64> {
+    let n = degree + 1;
+    let mut matrix = vec![vec![0.0; n + 1]; n];
+    for k in 0..x.len() {
+        let mut powers = vec![1.0; 2 * n - 1];
+        for p in 1..powers.len() {
+            powers[p] = powers[p - 1] * x[k];
+        }
+        for i in 0..n {
+            for j in 0..n {
+                matrix[i][j] += powers[i + j];
+            }
+            matrix[i][n] += powers[i] * y[k];
+        }
+    }
 
-            self.normalized_g[i] = self.g[i] as f64 / expected_value / self.counter as f64;
+    // Gaussian elimination with partial pivoting
+    for column in 0..n {
+        let mut pivot = column;
+        for row in column + 1..n {
+            if matrix[row][column].abs() > matrix[pivot][column].abs() {
+                pivot = row;
+            }
         }
+        matrix.swap(column, pivot);
+        let pivot_row = matrix[column].clone();
+        for row in matrix.iter_mut().take(n).skip(column + 1) {
+            let factor = row[column] / pivot_row[column];
+            for (value, pivot_value) in row.iter_mut().zip(pivot_row.iter()).skip(column) {
+                *value -= factor * pivot_value;
+            }
+        }
+    }
+    let mut coefficients = vec![0.0; n];
+    for i in (0..n).rev() {
+        let mut value = matrix[i][n];
+        for j in i + 1..n {
+            value -= matrix[i][j] * coefficients[j];
+        }
+        coefficients[i] = value / matrix[i][i];
+    }
+    coefficients
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_polynomial_fit() {
+        let x: Vec<f64> = (0..20).map(|i| i as f64 / 19.0).collect();
+        let y: Vec<f64> = x.iter().map(|x| 3.0 - 2.0 * x + 0.5 * x * x).collect();
+        let coefficients = polynomial_fit(&x, &y, 2);
+        assert!((coefficients[0] - 3.0).abs() < 1e-10);
+        assert!((coefficients[1] + 2.0).abs() < 1e-10);
+        assert!((coefficients[2] - 0.5).abs() < 1e-10);
     }
 }
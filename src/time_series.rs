@@ -0,0 +1,300 @@
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+
+use crate::config;
+use crate::defects;
+use crate::order;
+use crate::simulation;
+
+// Appends one CSV row every stride steps while the run goes, so long jobs
+// can be followed live and nothing is lost if they die. The first column
+// tells the equilibration from the production.
+pub struct TimeSeriesWriter {
+    filepath: PathBuf,
+    stride: u32,
+    nb_equilibration_steps: u32,
+    columns: Vec<Column>,
+    last_step: Option<u32>,
+    // Counters at the previous row, acceptance rates are over the last stride
+    last_displacement_trials: u64,
+    last_displacement_success: u64,
+    last_volume_trials: u64,
+    last_volume_success: u64,
+    rows: u64,
+}
+
+struct Column {
+    name: &'static str,
+    value: fn(&mut simulation::Simulation, &TimeSeriesWriter) -> f64,
+}
+
+impl TimeSeriesWriter {
+    pub fn from_config(
+        config: &config::Config,
+        simulation: &mut simulation::Simulation,
+    ) -> Option<TimeSeriesWriter> {
+        let time_series_config = config.time_series.as_ref()?;
+        if time_series_config.stride == 0 {
+            panic!("The stride of the time series must be at least one step");
+        }
+        let mut writer = TimeSeriesWriter {
+            filepath: PathBuf::from(
+                time_series_config
+                    .file
+                    .clone()
+                    .unwrap_or("time_series.csv".to_string()),
+            ),
+            stride: time_series_config.stride,
+            nb_equilibration_steps: config.n_equilibration_steps.unwrap_or(0),
+            columns: registered_columns(config),
+            last_step: None,
+            last_displacement_trials: simulation.nb_displacement_trials,
+            last_displacement_success: simulation.nb_displacement_success,
+            last_volume_trials: simulation.nb_volume_trials,
+            last_volume_success: simulation.nb_volume_success,
+            rows: 0,
+        };
+        match simulation.time_series_output {
+            // Restarted run, rows written after the checkpoint go away
+            Some(position) if simulation.step > 0 => {
+                position.truncate_text_file(&writer.filepath);
+                writer.last_step = position.last_step;
+                writer.rows = position.frames;
+            }
+            // New run, start from a file with the header and the initial
+            // configuration
+            _ => {
+                let mut file =
+                    File::create(&writer.filepath).expect("Could not create time series.");
+                let mut names: Vec<&str> = vec!["phase"];
+                names.extend(writer.columns.iter().map(|column| column.name));
+                writeln!(file, "{}", names.join(",")).unwrap();
+                writer.write_row(simulation);
+            }
+        }
+        Some(writer)
+    }
+
+    // Steps the samplers can do before the next row is due
+    pub fn steps_to_next_row(&self, step: u32) -> u32 {
+        self.stride - step % self.stride
+    }
+
+    // Once a multiple of stride is passed
+    pub fn write_if_needed(&mut self, simulation: &mut simulation::Simulation) {
+        if let Some(last_step) = self.last_step {
+            if simulation.step / self.stride == last_step / self.stride {
+                return;
+            }
+        }
+        self.write_row(simulation);
+    }
+
+    fn write_row(&mut self, simulation: &mut simulation::Simulation) {
+        let phase = if simulation.step < self.nb_equilibration_steps {
+            "equilibration"
+        } else {
+            "production"
+        };
+        let mut values: Vec<String> = vec![phase.to_string()];
+        values.extend(
+            self.columns
+                .iter()
+                .map(|column| (column.value)(simulation, self).to_string()),
+        );
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(&self.filepath)
+            .expect("Could not open time series.");
+        writeln!(file, "{}", values.join(",")).unwrap();
+
+        self.last_step = Some(simulation.step);
+        self.rows += 1;
+        simulation.time_series_output = Some(simulation::OutputPosition {
+            bytes: file.metadata().unwrap().len(),
+            frames: self.rows,
+            last_step: self.last_step,
+        });
+        self.last_displacement_trials = simulation.nb_displacement_trials;
+        self.last_displacement_success = simulation.nb_displacement_success;
+        self.last_volume_trials = simulation.nb_volume_trials;
+        self.last_volume_success = simulation.nb_volume_success;
+    }
+}
+
+// NaN when nothing was tried since the last row
+fn acceptance_rate(success: u64, trials: u64) -> f64 {
+    success as f64 / trials as f64
+}
+
+// Columns written for this configuration, new observables go here
+fn registered_columns(config: &config::Config) -> Vec<Column> {
+    let mut columns = vec![
+        Column {
+            name: "step",
+            value: |simulation, _| simulation.step as f64,
+        },
+        Column {
+            name: "nvt_acceptance_rate",
+            value: |simulation, writer| {
+                acceptance_rate(
+                    simulation.nb_displacement_success - writer.last_displacement_success,
+                    simulation.nb_displacement_trials - writer.last_displacement_trials,
+                )
+            },
+        },
+        Column {
+            name: "npt_acceptance_rate",
+            value: |simulation, writer| {
+                acceptance_rate(
+                    simulation.nb_volume_success - writer.last_volume_success,
+                    simulation.nb_volume_trials - writer.last_volume_trials,
+                )
+            },
+        },
+        Column {
+            name: "max_displacement",
+            value: |simulation, _| simulation.displacement.max_displacement,
+        },
+        Column {
+            name: "density",
+            value: |simulation, _| simulation.state.get_density(),
+        },
+        Column {
+            name: "number_density",
+            value: |simulation, _| simulation.state.get_number_density(),
+        },
+        Column {
+            name: "lx",
+            value: |simulation, _| simulation.state.sim_box.lx,
+        },
+        Column {
+            name: "ly",
+            value: |simulation, _| simulation.state.sim_box.ly,
+        },
+    ];
+    if config.algorithm == config::Algorithm::EventChain {
+        columns.push(Column {
+            name: "pressure",
+            // Mean of the last block of chains, the running estimate would
+            // hide any drift
+            value: |simulation, _| {
+                *simulation
+                    .pressure_blocks
+                    .block_means
+                    .last()
+                    .unwrap_or(&f64::NAN)
+            },
+        });
+    }
+    if config.psi6.is_some() {
+        columns.push(Column {
+            name: "psi6",
+            value: |simulation, _| {
+                // Not set up in every sampler
+                let psi6 = match simulation.thermo.psi6.as_ref() {
+                    Some(psi6) => psi6,
+                    None => return f64::NAN,
+                };
+                let (neighbors, cutoff) = (psi6.neighbors, psi6.cutoff);
+                let neighbors = order::find_neighbors(&simulation.state, neighbors, cutoff);
+                order::global_psi6(&order::local_psi6(&simulation.state, &neighbors))
+            },
+        });
+    }
+    if config.defects {
+        columns.push(Column {
+            name: "dislocations",
+            value: |simulation, _| {
+                let neighbors = order::voronoi_neighbors(&simulation.state);
+                defects::count_defects(&neighbors).dislocations as f64
+                    / simulation.state.disks.len() as f64
+            },
+        });
+    }
+    columns
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sample;
+
+    #[test]
+    fn test_restart_drops_rows_after_checkpoint() {
+        let time_series_path = std::env::temp_dir().join("hard-disks-test-restart.csv");
+        let checkpoint_path =
+            std::env::temp_dir().join("hard-disks-test-restart-checkpoint-csv.bin");
+        let config = |n_production_steps: u32| -> config::Config {
+            serde_yaml::from_str(&format!(
+                "{{n_disk: 16, packing_fraction: 0.5, n_production_steps: {}, seed: 3, \
+                algorithm: event_chain, checkpoint_interval: 16, checkpoint_file: {}, \
+                time_series: {{stride: 16, file: {}}}}}",
+                n_production_steps,
+                checkpoint_path.display(),
+                time_series_path.display()
+            ))
+            .unwrap()
+        };
+        let read_rows = || -> Vec<String> {
+            std::fs::read_to_string(&time_series_path)
+                .unwrap()
+                .lines()
+                .skip(1)
+                .map(|line| line.to_string())
+                .collect()
+        };
+        let full_run = config(320);
+        let mut simulation = sample::test_simulation(&full_run);
+        sample::sample_ecmc(&mut simulation, &full_run);
+        let uninterrupted_rows = read_rows();
+
+        let first_half = config(160);
+        let mut simulation = sample::test_simulation(&first_half);
+        sample::sample_ecmc(&mut simulation, &first_half);
+        // A row written after the checkpoint, before the run died
+        let mut resumed_simulation = simulation::Simulation::from_checkpoint(&checkpoint_path);
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(&time_series_path)
+            .unwrap();
+        writeln!(file, "999,0").unwrap();
+        sample::sample_ecmc(&mut resumed_simulation, &full_run);
+        let resumed_rows = read_rows();
+        std::fs::remove_file(&time_series_path).unwrap();
+        std::fs::remove_file(&checkpoint_path).unwrap();
+
+        assert_eq!(resumed_rows.len(), 21);
+        assert_eq!(resumed_rows, uninterrupted_rows);
+    }
+
+    #[test]
+    fn test_rows_through_the_equilibration() {
+        let time_series_path = std::env::temp_dir().join("hard-disks-test-phases.csv");
+        let config: config::Config = serde_yaml::from_str(&format!(
+            "{{n_disk: 16, packing_fraction: 0.5, n_equilibration_steps: 480, \
+            n_production_steps: 480, seed: 3, time_series: {{stride: 96, file: {}}}}}",
+            time_series_path.display()
+        ))
+        .unwrap();
+        let mut simulation = sample::test_simulation(&config);
+        sample::sample_nvt(&mut simulation, &config);
+        let rows: Vec<Vec<String>> = std::fs::read_to_string(&time_series_path)
+            .unwrap()
+            .lines()
+            .skip(1)
+            .map(|line| line.split(',').map(|value| value.to_string()).collect())
+            .collect();
+        std::fs::remove_file(&time_series_path).unwrap();
+
+        let steps: Vec<&str> = rows.iter().map(|row| row[1].as_str()).collect();
+        assert_eq!(
+            steps,
+            ["0", "96", "192", "288", "384", "480", "576", "672", "768", "864", "960"]
+        );
+        let phases: Vec<&str> = rows.iter().map(|row| row[0].as_str()).collect();
+        assert_eq!(phases[..5], ["equilibration"; 5]);
+        assert_eq!(phases[5..], ["production"; 6]);
+    }
+}
@@ -0,0 +1,308 @@
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+
+use crate::config;
+use crate::gsd;
+use crate::order;
+use crate::simulation;
+use crate::state;
+
+// Appends frames in extended XYZ format, readable by OVITO and ASE, or in
+// gsd format for HOOMD and freud
+pub struct TrajectoryWriter {
+    format: config::TrajectoryFormat,
+    filepath: PathBuf,
+    stride: u32,
+    properties: Vec<config::TrajectoryProperty>,
+    neighbors: config::NeighborMethod,
+    // Neighbor cutoff for the local ψ6
+    cutoff: Option<f64>,
+    last_step: Option<u32>,
+    frames: u64,
+    // Open for the whole run, gsd frames need the index read back otherwise
+    gsd_file: Option<gsd::GsdFile>,
+}
+
+impl TrajectoryWriter {
+    pub fn from_config(
+        config: &config::Config,
+        simulation: &mut simulation::Simulation,
+    ) -> Option<TrajectoryWriter> {
+        let trajectory_config = config.trajectory.as_ref()?;
+        let default_file = match trajectory_config.format {
+            config::TrajectoryFormat::Xyz => "trajectory.xyz",
+            config::TrajectoryFormat::Gsd => "trajectory.gsd",
+        };
+        let filepath = PathBuf::from(
+            trajectory_config
+                .file
+                .clone()
+                .unwrap_or(default_file.to_string()),
+        );
+        let mut gsd_file = None;
+        let position = match simulation.trajectory_output {
+            // Restarted run, frames written after the checkpoint go away
+            Some(position) if simulation.step > 0 => {
+                match trajectory_config.format {
+                    config::TrajectoryFormat::Xyz => position.truncate_text_file(&filepath),
+                    config::TrajectoryFormat::Gsd => {
+                        let mut file = gsd::GsdFile::open_for_append(&filepath);
+                        file.truncate_frames(position.frames);
+                        gsd_file = Some(file);
+                    }
+                }
+                position
+            }
+            // New run, start from an empty file
+            _ => {
+                match trajectory_config.format {
+                    config::TrajectoryFormat::Xyz => {
+                        File::create(&filepath).expect("Could not create trajectory file.");
+                    }
+                    config::TrajectoryFormat::Gsd => {
+                        gsd_file = Some(gsd::GsdFile::create(&filepath));
+                    }
+                }
+                simulation::OutputPosition::default()
+            }
+        };
+        simulation.trajectory_output = Some(position);
+        Some(TrajectoryWriter {
+            format: trajectory_config.format,
+            filepath,
+            stride: trajectory_config.stride,
+            properties: trajectory_config.properties.clone(),
+            neighbors: config
+                .psi6
+                .as_ref()
+                .map_or(config::NeighborMethod::Cutoff, |psi6| psi6.neighbors),
+            cutoff: config.psi6.as_ref().and_then(|psi6| psi6.cutoff),
+            last_step: position.last_step,
+            frames: position.frames,
+            gsd_file,
+        })
+    }
+
+    pub fn write_if_needed(&mut self, simulation: &mut simulation::Simulation) {
+        if let Some(last_step) = self.last_step {
+            if simulation.step - last_step < self.stride {
+                return;
+            }
+        }
+        self.write_frame(&simulation.state, simulation.step);
+        self.last_step = Some(simulation.step);
+        self.frames += 1;
+        simulation.trajectory_output = Some(simulation::OutputPosition {
+            bytes: std::fs::metadata(&self.filepath).unwrap().len(),
+            frames: self.frames,
+            last_step: self.last_step,
+        });
+    }
+
+    fn write_frame(&mut self, state: &state::State, step: u32) {
+        let psi6 = if self.properties.contains(&config::TrajectoryProperty::Psi6) {
+            let cutoff = self.cutoff.unwrap_or(1.4 * 2.0 * state.get_mean_radius());
+            let neighbors = order::find_neighbors(state, self.neighbors, cutoff);
+            order::local_psi6(state, &neighbors)
+        } else {
+            Vec::new()
+        };
+        match self.format {
+            config::TrajectoryFormat::Xyz => self.write_xyz_frame(state, step, &psi6),
+            config::TrajectoryFormat::Gsd => self.write_gsd_frame(state, step, &psi6),
+        }
+    }
+
+    fn write_gsd_frame(&mut self, state: &state::State, step: u32, psi6: &[order::Complex]) {
+        let mut logs: Vec<(&str, u32, gsd::ChunkData)> = Vec::new();
+        for property in self.properties.iter() {
+            match property {
+                config::TrajectoryProperty::Psi6 => logs.push((
+                    "psi6",
+                    2,
+                    gsd::ChunkData::Double(
+                        psi6.iter().flat_map(|value| [value.re, value.im]).collect(),
+                    ),
+                )),
+                config::TrajectoryProperty::CellId => logs.push((
+                    "cell_id",
+                    1,
+                    gsd::ChunkData::UInt32(
+                        state.disks.iter().map(|disk| disk.cell_id as u32).collect(),
+                    ),
+                )),
+                config::TrajectoryProperty::Displacement => logs.push((
+                    "displacement",
+                    2,
+                    gsd::ChunkData::Double(
+                        state
+                            .disks
+                            .iter()
+                            .flat_map(|disk| [disk.displacement.x, disk.displacement.y])
+                            .collect(),
+                    ),
+                )),
+            }
+        }
+        let gsd_file = self.gsd_file.as_mut().unwrap();
+        gsd::append_frame(gsd_file, state, step as u64, &logs);
+    }
+
+    fn write_xyz_frame(&self, state: &state::State, step: u32, psi6: &[order::Complex]) {
+        let file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(&self.filepath)
+            .expect("Could not open trajectory file.");
+        let mut writer = BufWriter::new(file);
+
+        let mut columns = "species:S:1:pos:R:3:radius:R:1".to_string();
+        for property in self.properties.iter() {
+            columns += match property {
+                config::TrajectoryProperty::Psi6 => ":psi6:R:2",
+                config::TrajectoryProperty::CellId => ":cell_id:I:1",
+                config::TrajectoryProperty::Displacement => ":displacement:R:2",
+            };
+        }
+        writeln!(writer, "{}", state.disks.len()).unwrap();
+        writeln!(
+            writer,
+            "Lattice=\"{} 0.0 0.0 0.0 {} 0.0 0.0 0.0 1.0\" Properties={} step={} pbc=\"T T F\"",
+            state.sim_box.lx, state.sim_box.ly, columns, step
+        )
+        .unwrap();
+        for (disk_id, disk) in state.disks.iter().enumerate() {
+            write!(
+                writer,
+                "{} {} {} 0.0 {}",
+                species_label(disk.species),
+                disk.position.x,
+                disk.position.y,
+                disk.radius
+            )
+            .unwrap();
+            for property in self.properties.iter() {
+                match property {
+                    config::TrajectoryProperty::Psi6 => {
+                        write!(writer, " {} {}", psi6[disk_id].re, psi6[disk_id].im).unwrap()
+                    }
+                    config::TrajectoryProperty::CellId => {
+                        write!(writer, " {}", disk.cell_id).unwrap()
+                    }
+                    config::TrajectoryProperty::Displacement => {
+                        write!(writer, " {} {}", disk.displacement.x, disk.displacement.y).unwrap()
+                    }
+                }
+            }
+            writeln!(writer).unwrap();
+        }
+    }
+}
+
+// A, B, C... as most viewers expect element-like names
+fn species_label(species: u32) -> String {
+    if species < 26 {
+        return char::from(b'A' + species as u8).to_string();
+    }
+    format!("S{}", species)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sample;
+
+    #[test]
+    fn test_write_frames() {
+        let state = state::State::hexagonal_packing(4, 4, 0.6);
+        let filepath = std::env::temp_dir().join("hard-disks-test-trajectory.xyz");
+        File::create(&filepath).unwrap();
+        let mut writer = TrajectoryWriter {
+            format: config::TrajectoryFormat::Xyz,
+            filepath: filepath.clone(),
+            stride: 1,
+            properties: vec![
+                config::TrajectoryProperty::Psi6,
+                config::TrajectoryProperty::CellId,
+            ],
+            neighbors: config::NeighborMethod::Voronoi,
+            cutoff: None,
+            last_step: None,
+            frames: 0,
+            gsd_file: None,
+        };
+        writer.write_frame(&state, 0);
+        writer.write_frame(&state, 10);
+        let contents = std::fs::read_to_string(&filepath).unwrap();
+        std::fs::remove_file(&filepath).unwrap();
+
+        let lines: Vec<&str> = contents.lines().collect();
+        assert_eq!(lines.len(), 2 * 18);
+        assert_eq!(lines[18], "16");
+        assert!(
+            lines[19].contains("Properties=species:S:1:pos:R:3:radius:R:1:psi6:R:2:cell_id:I:1")
+        );
+        assert!(lines[19].contains("step=10"));
+        // Species, 3 coordinates, radius, 2 for ψ6 and the cell id
+        assert_eq!(lines[20].split_whitespace().count(), 8);
+    }
+
+    #[test]
+    fn test_restart_drops_frames_after_checkpoint() {
+        for format in ["xyz", "gsd"] {
+            let trajectory_path =
+                std::env::temp_dir().join(format!("hard-disks-test-restart.{}", format));
+            let checkpoint_path = std::env::temp_dir()
+                .join(format!("hard-disks-test-restart-checkpoint-{}.bin", format));
+            let config = |n_production_steps: u32| -> config::Config {
+                serde_yaml::from_str(&format!(
+                    "{{n_disk: 16, packing_fraction: 0.5, n_production_steps: {}, seed: 3, \
+                    checkpoint_interval: 1600, checkpoint_file: {}, \
+                    trajectory: {{format: {}, stride: 1600, file: {}}}}}",
+                    n_production_steps,
+                    checkpoint_path.display(),
+                    format,
+                    trajectory_path.display()
+                ))
+                .unwrap()
+            };
+            let first_half = config(3200);
+            let mut simulation = sample::test_simulation(&first_half);
+            sample::sample_nvt(&mut simulation, &first_half);
+
+            // A frame written after the checkpoint, before the run died
+            let mut resumed_simulation = simulation::Simulation::from_checkpoint(&checkpoint_path);
+            match format {
+                "xyz" => {
+                    let mut file = OpenOptions::new()
+                        .append(true)
+                        .open(&trajectory_path)
+                        .unwrap();
+                    writeln!(file, "16\nLattice=\"truncated frame").unwrap();
+                }
+                _ => gsd::append_frame(
+                    &mut gsd::GsdFile::open_for_append(&trajectory_path),
+                    &resumed_simulation.state,
+                    0,
+                    &[],
+                ),
+            }
+            let full_run = config(6400);
+            sample::sample_nvt(&mut resumed_simulation, &full_run);
+
+            let number_of_frames = match format {
+                "xyz" => std::fs::read_to_string(&trajectory_path)
+                    .unwrap()
+                    .lines()
+                    .filter(|line| line.starts_with("Lattice="))
+                    .count() as u64,
+                _ => gsd::GsdFile::open(&trajectory_path).number_of_frames(),
+            };
+            std::fs::remove_file(&trajectory_path).unwrap();
+            std::fs::remove_file(&checkpoint_path).unwrap();
+            // One frame every 1600 steps of the production
+            assert_eq!(number_of_frames, 4);
+        }
+    }
+}
@@ -0,0 +1,236 @@
+use serde::{Deserialize, Serialize};
+
+use crate::geometry;
+use crate::state;
+
+// Cell of the radical (Laguerre) tessellation, which is the Voronoi
+// tessellation for equal radii and stays faithful to the disks otherwise
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct VoronoiCell {
+    // Relative to the disk center, counterclockwise
+    pub vertices: Vec<(f64, f64)>,
+    // Neighbor across the edge going from vertex k to vertex k + 1
+    pub neighbors: Vec<usize>,
+    pub edge_lengths: Vec<f64>,
+    pub area: f64,
+    pub perimeter: f64,
+}
+
+impl VoronoiCell {
+    pub fn number_of_sides(&self) -> usize {
+        self.neighbors.len()
+    }
+}
+
+struct Polygon {
+    vertices: Vec<(f64, f64)>,
+    // Disk whose radical line carries the edge from vertex k to vertex k + 1,
+    // None for the sides of the starting box
+    edges: Vec<Option<usize>>,
+}
+
+impl Polygon {
+    // Everything closer to the disk than to its own periodic images
+    fn from_box(sim_box: &geometry::Box) -> Polygon {
+        let hx = sim_box.lx / 2.0;
+        let hy = sim_box.ly / 2.0;
+        Polygon {
+            vertices: vec![(-hx, -hy), (hx, -hy), (hx, hy), (-hx, hy)],
+            edges: vec![None; 4],
+        }
+    }
+
+    fn max_vertex_distance(&self) -> f64 {
+        self.vertices
+            .iter()
+            .map(|(x, y)| (x * x + y * y).sqrt())
+            .fold(0.0, f64::max)
+    }
+
+    // Keep the part where p.normal <= offset
+    fn clip(&mut self, normal: (f64, f64), offset: f64, neighbor: usize) {
+        let side = |p: &(f64, f64)| p.0 * normal.0 + p.1 * normal.1 - offset;
+        if self.vertices.iter().all(|p| side(p) <= 0.0) {
+            return;
+        }
+        let n = self.vertices.len();
+        let mut vertices: Vec<(f64, f64)> = Vec::new();
+        let mut edges: Vec<Option<usize>> = Vec::new();
+        for k in 0..n {
+            let current = self.vertices[k];
+            let next = self.vertices[(k + 1) % n];
+            let side_current = side(&current);
+            let side_next = side(&next);
+            if side_current <= 0.0 {
+                vertices.push(current);
+                edges.push(self.edges[k]);
+            }
+            if (side_current <= 0.0) != (side_next <= 0.0) {
+                let t = side_current / (side_current - side_next);
+                vertices.push((
+                    current.0 + t * (next.0 - current.0),
+                    current.1 + t * (next.1 - current.1),
+                ));
+                // Leaving the half plane, the new edge runs along the cut
+                if side_current <= 0.0 {
+                    edges.push(Some(neighbor));
+                } else {
+                    edges.push(self.edges[k]);
+                }
+            }
+        }
+        self.vertices = vertices;
+        self.edges = edges;
+    }
+}
+
+// Periodic tessellation of the whole state, one cell per disk
+pub fn tessellate(state: &state::State) -> Vec<VoronoiCell> {
+    let max_radius = state
+        .disks
+        .iter()
+        .map(|disk| disk.radius)
+        .fold(0.0, f64::max);
+    (0..state.disks.len())
+        .map(|disk_id| compute_cell(state, disk_id, max_radius))
+        .collect()
+}
+
+fn compute_cell(state: &state::State, disk_id: usize, max_radius: f64) -> VoronoiCell {
+    let sim_box = &state.sim_box;
+    let mut polygon = Polygon::from_box(sim_box);
+
+    // The neighboring cells are enough when every disk that could still cut
+    // the polygon is closer than a cell width
+    let search_distance = state.get_cell_size().min(sim_box.lx.min(sim_box.ly) / 2.0);
+    let mut candidates: Vec<(usize, f64, f64)> = Vec::new();
+    for neighbor_id in state.get_neighbor_disks(disk_id) {
+        let (dx, dy) = separation(state, disk_id, neighbor_id);
+        candidates.push((neighbor_id, dx, dy));
+    }
+    clip_by_candidates(state, disk_id, &mut polygon, &mut candidates, max_radius);
+
+    if reach(&polygon, max_radius) > search_distance {
+        // Sparse system or tiny box, fall back to every periodic image
+        polygon = Polygon::from_box(sim_box);
+        candidates.clear();
+        for neighbor_id in 0..state.disks.len() {
+            let (dx, dy) = separation(state, disk_id, neighbor_id);
+            for ix in -1..=1 {
+                for iy in -1..=1 {
+                    if neighbor_id == disk_id && ix == 0 && iy == 0 {
+                        continue;
+                    }
+                    candidates.push((
+                        neighbor_id,
+                        dx + ix as f64 * sim_box.lx,
+                        dy + iy as f64 * sim_box.ly,
+                    ));
+                }
+            }
+        }
+        clip_by_candidates(state, disk_id, &mut polygon, &mut candidates, max_radius);
+    }
+
+    let n = polygon.vertices.len();
+    let mut area = 0.0;
+    let mut perimeter = 0.0;
+    let mut neighbors: Vec<usize> = Vec::new();
+    let mut edge_lengths: Vec<f64> = Vec::new();
+    for k in 0..n {
+        let (x1, y1) = polygon.vertices[k];
+        let (x2, y2) = polygon.vertices[(k + 1) % n];
+        area += 0.5 * (x1 * y2 - x2 * y1);
+        let length = ((x2 - x1) * (x2 - x1) + (y2 - y1) * (y2 - y1)).sqrt();
+        perimeter += length;
+        // The starting box only survives when the disk is its own neighbor
+        neighbors.push(polygon.edges[k].unwrap_or(disk_id));
+        edge_lengths.push(length);
+    }
+    VoronoiCell {
+        vertices: polygon.vertices,
+        neighbors,
+        edge_lengths,
+        area,
+        perimeter,
+    }
+}
+
+fn separation(state: &state::State, disk_id: usize, neighbor_id: usize) -> (f64, f64) {
+    let mut dx = state.disks[neighbor_id].position.x - state.disks[disk_id].position.x;
+    let mut dy = state.disks[neighbor_id].position.y - state.disks[disk_id].position.y;
+    geometry::apply_boundary_conditions(&mut dx, &mut dy, &state.sim_box);
+    (dx, dy)
+}
+
+// Distance beyond which no disk can cut the polygon anymore: the radical
+// plane of a disk at d is at (d² + r_i² - r_j²) / 2d from the center
+fn reach(polygon: &Polygon, max_radius: f64) -> f64 {
+    let r = polygon.max_vertex_distance();
+    r + (r * r + max_radius * max_radius).sqrt()
+}
+
+fn clip_by_candidates(
+    state: &state::State,
+    disk_id: usize,
+    polygon: &mut Polygon,
+    candidates: &mut [(usize, f64, f64)],
+    max_radius: f64,
+) {
+    // Closest first, so the polygon shrinks fast and we can stop early
+    candidates.sort_by(|a, b| {
+        (a.1 * a.1 + a.2 * a.2)
+            .partial_cmp(&(b.1 * b.1 + b.2 * b.2))
+            .unwrap()
+    });
+    let radius_sq = state.disks[disk_id].radius * state.disks[disk_id].radius;
+    for (neighbor_id, dx, dy) in candidates.iter() {
+        let d_sq = dx * dx + dy * dy;
+        if d_sq.sqrt() > reach(polygon, max_radius) {
+            break;
+        }
+        let neighbor_radius = state.disks[*neighbor_id].radius;
+        // |p|² - r_i² <= |p - d|² - r_j²
+        let offset = 0.5 * (d_sq + radius_sq - neighbor_radius * neighbor_radius);
+        polygon.clip((*dx, *dy), offset, *neighbor_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hexagonal_lattice() {
+        let state = state::State::hexagonal_packing(8, 8, 0.7);
+        let cells = tessellate(&state);
+        let box_area = state.sim_box.lx * state.sim_box.ly;
+        for cell in cells.iter() {
+            assert_eq!(cell.number_of_sides(), 6);
+            assert!((cell.area - box_area / 64.0).abs() < 1e-10);
+        }
+    }
+
+    #[test]
+    fn test_areas_fill_the_box() {
+        let mut state = state::State::hexagonal_packing(6, 6, 0.4);
+        // Shake the lattice so the cells are irregular
+        for disk_id in 0..state.disks.len() {
+            let shift = 0.2 * ((disk_id * 7 % 11) as f64 / 11.0 - 0.5);
+            state.update_disk_coordinates(
+                disk_id,
+                state.disks[disk_id].position.x + shift,
+                state.disks[disk_id].position.y - 0.5 * shift,
+            );
+        }
+        let cells = tessellate(&state);
+        let total_area: f64 = cells.iter().map(|cell| cell.area).sum();
+        assert!((total_area - state.sim_box.lx * state.sim_box.ly).abs() < 1e-8);
+        // Neighborhood is symmetric
+        for (disk_id, cell) in cells.iter().enumerate() {
+            for neighbor_id in cell.neighbors.iter() {
+                assert!(cells[*neighbor_id].neighbors.contains(&disk_id));
+            }
+        }
+    }
+}
@@ -0,0 +1,98 @@
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::geometry;
+use crate::sample;
+use crate::state;
+
+// Ghost disks dropped at random in the current configuration, from Widom,
+// J. Chem. Phys. 39, 2808 (1963): βμ_ex = -ln <P_insert>, with P_insert the
+// fraction that fits without overlap. At constant pressure the average is
+// weighted by the volume, βμ_ex = -ln(<V P_insert> / <V>). The configuration
+// is never changed.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WidomInsertion {
+    pub radius: f64,
+    // Ghost insertions per sample
+    pub insertions: u32,
+    pub step: Vec<u32>,
+    pub insertion_probability: Vec<f64>,
+    pub volume: Vec<f64>,
+    // Own stream, so the run is the same with or without the ghosts
+    rng: sample::SimulationRng,
+}
+
+impl WidomInsertion {
+    // From the stream of the simulation, which differs between replicas
+    pub fn new(radius: f64, insertions: u32, rng: &sample::SimulationRng) -> WidomInsertion {
+        let mut rng = rng.clone();
+        // Far from the streams of the simulation and of the other replicas
+        rng.long_jump();
+        WidomInsertion {
+            radius,
+            insertions,
+            step: Vec::new(),
+            insertion_probability: Vec::new(),
+            volume: Vec::new(),
+            rng,
+        }
+    }
+
+    pub fn update(&mut self, state: &state::State, step: u32) {
+        let mut nb_success = 0;
+        for _ in 0..self.insertions {
+            let position = geometry::Position {
+                x: self.rng.gen::<f64>() * state.sim_box.lx,
+                y: self.rng.gen::<f64>() * state.sim_box.ly,
+            };
+            if !state.is_position_overlapping(&position, self.radius) {
+                nb_success += 1;
+            }
+        }
+        self.step.push(step);
+        self.insertion_probability
+            .push(nb_success as f64 / self.insertions as f64);
+        self.volume.push(state.sim_box.lx * state.sim_box.ly);
+    }
+
+    // R + V (P_insert - R) / <V> for each sample, with R = <V P_insert> / <V>.
+    // The mean is R, and the fluctuations include those of the volume, for
+    // the error bar of the ratio. R is the plain mean at constant volume.
+    pub fn weighted_insertion_probability(&self, first_sample: usize) -> Vec<f64> {
+        let first_sample = first_sample.min(self.volume.len());
+        let volumes = &self.volume[first_sample..];
+        let probabilities = &self.insertion_probability[first_sample..];
+        let volume_sum = volumes.iter().sum::<f64>();
+        let mean_volume = volume_sum / volumes.len() as f64;
+        let ratio = volumes
+            .iter()
+            .zip(probabilities.iter())
+            .map(|(volume, probability)| volume * probability)
+            .sum::<f64>()
+            / volume_sum;
+        volumes
+            .iter()
+            .zip(probabilities.iter())
+            .map(|(volume, probability)| ratio + volume * (probability - ratio) / mean_volume)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dilute_lattice() {
+        // Exclusion circles of radius σ don’t overlap on this lattice, the
+        // free area is A - N π σ², so P_insert = 1 - 4φ
+        let state = state::State::hexagonal_packing(4, 4, 0.1);
+        let (rng, _) = sample::create_rng(Some(42));
+        let mut widom = WidomInsertion::new(0.5, 20000, &rng);
+        for step in 0..10 {
+            widom.update(&state, step);
+        }
+        let mean = widom.insertion_probability.iter().sum::<f64>() / 10.0;
+        assert!((-mean.ln() - -(0.6f64.ln())).abs() < 0.01);
+    }
+}